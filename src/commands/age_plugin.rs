@@ -0,0 +1,370 @@
+use crate::util::op::split_reference;
+use crate::util::op_key::{age_identity, read_age_key};
+use crate::util::sops_config::read_or_create_config;
+use age::Identity as _;
+use age::secrecy::ExposeSecret;
+use age::x25519;
+use age_core::format::{FileKey, Stanza};
+use age_core::plugin::{Connection, IDENTITY_V1};
+use bech32::{FromBase32, ToBase32, Variant};
+use colored::Colorize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the binary age clients run for `AGE-PLUGIN-OPSOPS-1...` identities, a link to opsops
+pub const PLUGIN_BINARY: &str = "age-plugin-opsops";
+
+/// Human readable part of the identities, the payload is the `op://` reference of the key
+const IDENTITY_PREFIX: &str = "age-plugin-opsops-";
+
+/// Stanza tag of files encrypted for an X25519 (`age1...`) recipient
+const X25519_TAG: &str = "X25519";
+
+/// Returns true if opsops runs as `age-plugin-opsops`. The rust age crate resolves the link
+/// before running it, so a first argument of `--age-plugin=...` counts as well.
+pub fn invoked_as_plugin() -> bool {
+    let mut args = std::env::args_os();
+    let named = args
+        .next()
+        .map(PathBuf::from)
+        .and_then(|path| path.file_name().map(|name| name == PLUGIN_BINARY))
+        .unwrap_or(false);
+    let state_machine = args
+        .next()
+        .is_some_and(|arg| arg.to_string_lossy().starts_with("--age-plugin="));
+    named || state_machine
+}
+
+/// Entry point for `age-plugin-opsops`: age and sops run it with `--age-plugin=identity-v1` to
+/// decrypt files for an opsops identity, which reads the key from 1Password (or the agent)
+pub fn run_plugin() -> ! {
+    let state_machine = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.strip_prefix("--age-plugin=").map(str::to_string));
+
+    match state_machine.as_deref() {
+        Some(IDENTITY_V1) => match identity_v1() {
+            Ok(()) => std::process::exit(0),
+            Err(e) => {
+                eprintln!("{} {}", "❌ age plugin protocol error:".red(), e);
+                std::process::exit(1);
+            }
+        },
+        Some(other) => {
+            eprintln!(
+                "{} {}",
+                "❌ age-plugin-opsops only decrypts, unsupported state machine:".red(),
+                other
+            );
+            std::process::exit(1);
+        }
+        None => {
+            eprintln!(
+                "{}",
+                "age-plugin-opsops is run by age and sops. Create an identity with `opsops age-plugin identity`."
+                    .dimmed()
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Entry point for `age-plugin identity`: prints the plugin identity for a key in 1Password,
+/// to put in an age identity file such as `SOPS_AGE_KEY_FILE`
+pub fn age_plugin_identity(item: Option<String>) {
+    let config = match read_or_create_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to read SOPS config:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let reference = match item {
+        Some(item) => config.onepassworditems.get(&item).cloned().unwrap_or(item),
+        None if !config.onepassworditem.is_empty() => config.onepassworditem.clone(),
+        None => {
+            eprintln!(
+                "{}",
+                "❌ No onepassworditem in .sops.yaml, use --item to choose a key.".red()
+            );
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = split_reference(&reference) {
+        eprintln!("{} {}", "❌".red(), e);
+        std::process::exit(1);
+    }
+
+    match encode_identity(&reference) {
+        Ok(identity) => {
+            println!("# opsops identity for {}", reference);
+            println!("{}", identity);
+        }
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to encode the identity:".red(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Entry point for `age-plugin install`: links `age-plugin-opsops` to this binary, so age and
+/// sops find the plugin on the PATH
+pub fn age_plugin_install(dir: Option<OsString>) {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to locate the opsops binary:".red(), e);
+            std::process::exit(1);
+        }
+    };
+    let dir = dir
+        .map(PathBuf::from)
+        .or_else(|| exe.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+    let link = dir.join(PLUGIN_BINARY);
+
+    if link.read_link().is_ok_and(|target| target == exe) {
+        println!(
+            "{} {}",
+            "✅ The age plugin is already installed at".green(),
+            link.display()
+        );
+        return;
+    }
+    if let Err(e) = std::os::unix::fs::symlink(&exe, &link) {
+        eprintln!("{} {}: {}", "❌ Failed to create".red(), link.display(), e);
+        std::process::exit(1);
+    }
+    println!(
+        "{} {}",
+        "✅ Installed the age plugin at".green(),
+        link.display()
+    );
+    if !is_on_path(&dir) {
+        println!(
+            "{} {}",
+            "⚠️  Add this directory to your PATH so age and sops find it:".yellow(),
+            dir.display()
+        );
+    }
+}
+
+fn is_on_path(dir: &Path) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|entry| entry == dir))
+}
+
+/// `AGE-PLUGIN-OPSOPS-1...` for a 1Password reference
+pub fn encode_identity(reference: &str) -> Result<String, String> {
+    bech32::encode(
+        IDENTITY_PREFIX,
+        reference.as_bytes().to_base32(),
+        Variant::Bech32,
+    )
+    .map(|identity| identity.to_uppercase())
+    .map_err(|e| e.to_string())
+}
+
+/// The 1Password reference inside an opsops identity
+fn decode_identity(identity: &str) -> Result<String, String> {
+    let (hrp, data, variant) = bech32::decode(identity).map_err(|e| e.to_string())?;
+    if hrp != IDENTITY_PREFIX || variant != Variant::Bech32 {
+        return Err("not an opsops identity".to_string());
+    }
+    let bytes = Vec::<u8>::from_base32(&data).map_err(|e| e.to_string())?;
+    let reference = String::from_utf8(bytes).map_err(|e| e.to_string())?;
+    split_reference(&reference)?;
+    Ok(reference)
+}
+
+/// A problem reported back to the age client, which shows it to the user
+enum PluginError {
+    Identity { index: usize, message: String },
+    Stanza { file_index: usize, message: String },
+    Internal(String),
+}
+
+/// The identity-v1 state machine: the client sends identities and the recipient stanzas of
+/// each file, opsops answers with the file keys it can unwrap
+fn identity_v1() -> io::Result<()> {
+    let mut connection = Connection::accept();
+
+    let (identities, stanzas, _, _) = connection.unidir_receive(
+        ("add-identity", |s: Stanza| match s.args.as_slice() {
+            [identity] => decode_identity(identity)
+                .map_err(|e| PluginError::Internal(format!("invalid identity: {}", e))),
+            _ => Err(PluginError::Internal(
+                "add-identity takes one identity".to_string(),
+            )),
+        }),
+        ("recipient-stanza", |s: Stanza| match s.args.as_slice() {
+            [file_index, tag, args @ ..] => file_index
+                .parse::<usize>()
+                .map(|file_index| {
+                    let stanza = Stanza {
+                        tag: tag.clone(),
+                        args: args.to_vec(),
+                        body: s.body.clone(),
+                    };
+                    (file_index, stanza)
+                })
+                .map_err(|_| PluginError::Internal("invalid file index".to_string())),
+            _ => Err(PluginError::Internal(
+                "recipient-stanza needs a file index and a tag".to_string(),
+            )),
+        }),
+        (None, |_: Stanza| Ok::<(), PluginError>(())),
+        (None, |_: Stanza| Ok::<(), PluginError>(())),
+    )?;
+
+    let (identities, files, mut errors) = match (identities, stanzas) {
+        (Ok(identities), Ok(stanzas)) => {
+            let mut files: BTreeMap<usize, Vec<Stanza>> = BTreeMap::new();
+            for (file_index, stanza) in stanzas {
+                files.entry(file_index).or_default().push(stanza);
+            }
+            (identities, files, Vec::new())
+        }
+        (identities, stanzas) => {
+            let errors = identities
+                .err()
+                .into_iter()
+                .chain(stanzas.err())
+                .flatten()
+                .collect();
+            (Vec::new(), BTreeMap::new(), errors)
+        }
+    };
+
+    // Only X25519 stanzas can be for a key from 1Password, don't ask for it otherwise
+    let wanted = files
+        .values()
+        .any(|stanzas| stanzas.iter().any(|s| s.tag == X25519_TAG));
+
+    let mut file_keys = BTreeMap::new();
+    for (index, reference) in identities.iter().enumerate() {
+        if !wanted || file_keys.len() == files.len() {
+            break;
+        }
+        let identity = match read_age_key(reference).and_then(|key| age_identity(&key)) {
+            Ok(identity) => identity,
+            Err(message) => {
+                errors.push(PluginError::Identity { index, message });
+                continue;
+            }
+        };
+
+        unwrap_file_keys(&identity, &files, &mut file_keys, &mut errors);
+    }
+
+    connection.bidir_send(|mut phase| {
+        for error in &errors {
+            let (metadata, message) = match error {
+                PluginError::Identity { index, message } => {
+                    (vec!["identity".to_string(), index.to_string()], message)
+                }
+                PluginError::Stanza {
+                    file_index,
+                    message,
+                } => (vec!["stanza".to_string(), file_index.to_string()], message),
+                PluginError::Internal(message) => (vec!["internal".to_string()], message),
+            };
+            let metadata: Vec<&str> = metadata.iter().map(String::as_str).collect();
+            phase
+                .send("error", &metadata, message.trim().as_bytes())?
+                .ok();
+        }
+        for (file_index, file_key) in &file_keys {
+            phase
+                .send(
+                    "file-key",
+                    &[&file_index.to_string()],
+                    file_key.expose_secret(),
+                )?
+                .ok();
+        }
+        Ok(())
+    })
+}
+
+/// Unwraps the file keys of the files `identity` is a recipient of, skipping files whose key is
+/// known already
+fn unwrap_file_keys(
+    identity: &x25519::Identity,
+    files: &BTreeMap<usize, Vec<Stanza>>,
+    file_keys: &mut BTreeMap<usize, FileKey>,
+    errors: &mut Vec<PluginError>,
+) {
+    for (file_index, stanzas) in files {
+        if file_keys.contains_key(file_index) {
+            continue;
+        }
+        match identity.unwrap_stanzas(stanzas) {
+            Some(Ok(file_key)) => {
+                file_keys.insert(*file_index, file_key);
+            }
+            Some(Err(e)) => errors.push(PluginError::Stanza {
+                file_index: *file_index,
+                message: e.to_string(),
+            }),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::Recipient as _;
+
+    const REFERENCE: &str = "op://Private/age key/Private Key";
+
+    #[test]
+    fn identities_round_trip_their_reference() {
+        let identity = encode_identity(REFERENCE).unwrap();
+        assert!(identity.starts_with("AGE-PLUGIN-OPSOPS-1"), "{}", identity);
+        assert_eq!(decode_identity(&identity).unwrap(), REFERENCE);
+        assert_eq!(
+            decode_identity(&identity.to_lowercase()).unwrap(),
+            REFERENCE
+        );
+
+        let other = bech32::encode(
+            "age-plugin-other-",
+            REFERENCE.as_bytes().to_base32(),
+            Variant::Bech32,
+        )
+        .unwrap();
+        assert!(decode_identity(&other).is_err());
+        assert!(decode_identity(&encode_identity("not a reference").unwrap()).is_err());
+    }
+
+    #[test]
+    fn unwraps_the_file_key_of_an_x25519_stanza() {
+        let identity = x25519::Identity::generate();
+        let file_key = FileKey::new(Box::new([7; 16]));
+        let (stanzas, _) = identity.to_public().wrap_file_key(&file_key).unwrap();
+        let files = BTreeMap::from([
+            (0, stanzas),
+            (
+                1,
+                x25519::Identity::generate()
+                    .to_public()
+                    .wrap_file_key(&file_key)
+                    .unwrap()
+                    .0,
+            ),
+        ]);
+
+        let mut file_keys = BTreeMap::new();
+        let mut errors = Vec::new();
+        unwrap_file_keys(&identity, &files, &mut file_keys, &mut errors);
+
+        assert!(errors.is_empty());
+        assert_eq!(file_keys.len(), 1);
+        assert_eq!(file_keys[&0].expose_secret(), &[7; 16]);
+    }
+}
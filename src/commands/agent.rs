@@ -0,0 +1,267 @@
+use crate::util::agent::{CachedKey, Request, Response, agent_request, socket_path};
+use crate::util::private_socket::{bind_private_socket, peer_is_user};
+use crate::util::secret_memory::disable_core_dumps;
+use age::secrecy::ExposeSecret;
+use age::x25519::Identity;
+use colored::Colorize;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest request line read, far more than a request holding a key needs
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// How often expired keys are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Entry {
+    identity: Identity,
+    expires: Instant,
+}
+
+type Keys = Arc<Mutex<HashMap<String, Entry>>>;
+
+/// Entry point for `agent`: keeps keys read from 1Password in memory for `ttl`, so commands
+/// don't run `op read` (and its prompt) every time. Runs in the foreground until interrupted.
+pub fn agent(ttl: String) {
+    let ttl = match humantime::parse_duration(&ttl) {
+        Ok(ttl) if !ttl.is_zero() => ttl,
+        Ok(_) => {
+            eprintln!("{}", "❌ The TTL has to be longer than zero.".red());
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{} {}: {}", "❌ Invalid TTL".red(), ttl, e);
+            std::process::exit(1);
+        }
+    };
+
+    let path = socket_path();
+    let listener = match bind_private_socket(&path, "An agent") {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("{} {}", "❌".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    // The agent holds keys for as long as it runs
+    disable_core_dumps();
+    let keys: Keys = Arc::default();
+    if let Err(e) = stop_on_signals(&keys, &path) {
+        eprintln!("{} {}", "❌ Failed to install signal handlers:".red(), e);
+        let _ = fs::remove_file(&path);
+        std::process::exit(1);
+    }
+    let sweeper = Arc::clone(&keys);
+    thread::spawn(move || {
+        loop {
+            thread::sleep(SWEEP_INTERVAL);
+            let now = Instant::now();
+            sweeper
+                .lock()
+                .unwrap()
+                .retain(|_, entry| entry.expires > now);
+        }
+    });
+
+    println!(
+        "{} {}",
+        "🔐 opsops agent listening on".green(),
+        path.display()
+    );
+    println!(
+        "{}",
+        format!(
+            "Keys are kept for {}, stop the agent with Ctrl-C or forget them with `opsops agent lock`.",
+            humantime::format_duration(ttl)
+        )
+        .dimmed()
+    );
+
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        if let Err(e) = serve(stream, &keys, ttl) {
+            eprintln!("{} {}", "⚠️  Agent request failed:".yellow(), e);
+        }
+    }
+}
+
+/// Entry point for `agent lock`: makes the running agent forget every key
+pub fn agent_lock() {
+    match agent_request(&Request::Lock) {
+        Some(Response::Locked { forgotten }) => {
+            println!(
+                "{} {}",
+                "🔒 Agent locked, keys forgotten:".green(),
+                forgotten
+            );
+        }
+        Some(_) => {
+            eprintln!("{}", "❌ The agent refused to lock.".red());
+            std::process::exit(1);
+        }
+        None => {
+            println!("{}", "ℹ️  No agent is running.".blue());
+        }
+    }
+}
+
+/// Entry point for `agent status`: shows the keys the running agent holds
+pub fn agent_status() {
+    let Some(Response::Status { ttl_seconds, keys }) = agent_request(&Request::Status) else {
+        println!(
+            "{} {}",
+            "ℹ️  No agent is running on".blue(),
+            socket_path().display()
+        );
+        std::process::exit(1);
+    };
+
+    println!(
+        "{} {} {}",
+        "🔐 Agent running on".green(),
+        socket_path().display(),
+        format!(
+            "(keys are kept for {})",
+            humantime::format_duration(Duration::from_secs(ttl_seconds))
+        )
+        .dimmed()
+    );
+    if keys.is_empty() {
+        println!("{}", "No keys cached.".dimmed());
+    }
+    for key in keys {
+        println!(
+            "  {} {} {}",
+            format!("{}:", key.reference).cyan(),
+            key.public_key.green(),
+            format!(
+                "(expires in {})",
+                humantime::format_duration(Duration::from_secs(key.expires_in_seconds))
+            )
+            .dimmed()
+        );
+    }
+}
+
+/// Forget the keys and remove the socket when the agent is stopped
+fn stop_on_signals(keys: &Keys, path: &Path) -> std::io::Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    let keys = Arc::clone(keys);
+    let path = path.to_path_buf();
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            keys.lock().unwrap().clear();
+            let _ = fs::remove_file(&path);
+            println!("{}", "🔒 Agent stopped".green());
+            std::process::exit(0);
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: UnixStream, keys: &Keys, ttl: Duration) -> Result<(), String> {
+    if !peer_is_user(&stream) {
+        return Err("refused a connection from another user".to_string());
+    }
+    stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+
+    // Requests and replies may hold a private key. The buffer is sized up front, so growing it
+    // leaves no copies behind.
+    let mut line = Zeroizing::new(String::with_capacity(MAX_REQUEST_SIZE));
+    BufReader::new(&stream)
+        .take(MAX_REQUEST_SIZE as u64)
+        .read_line(&mut line)
+        .map_err(|e| format!("Failed to read request: {}", e))?;
+    if line.len() == MAX_REQUEST_SIZE && !line.ends_with('\n') {
+        return Err(format!(
+            "refused a request longer than {} bytes",
+            MAX_REQUEST_SIZE
+        ));
+    }
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => handle(request, keys, ttl),
+        Err(e) => Response::Error {
+            message: format!("Invalid request: {}", e),
+        },
+    };
+
+    let mut reply = Zeroizing::new(serde_json::to_string(&response).map_err(|e| e.to_string())?);
+    reply.push('\n');
+    (&stream)
+        .write_all(reply.as_bytes())
+        .map_err(|e| format!("Failed to reply: {}", e))
+}
+
+fn handle(request: Request, keys: &Keys, ttl: Duration) -> Response {
+    let mut keys = keys.lock().unwrap();
+    let now = Instant::now();
+    keys.retain(|_, entry| entry.expires > now);
+
+    match request {
+        Request::Get { reference } => match keys.get(&reference) {
+            Some(entry) => Response::Key {
+                key: Zeroizing::new(entry.identity.to_string().expose_secret().to_string()),
+            },
+            None => Response::NotCached,
+        },
+        Request::PublicKey { reference } => match keys.get(&reference) {
+            Some(entry) => Response::PublicKey {
+                public_key: entry.identity.to_public().to_string(),
+            },
+            None => Response::NotCached,
+        },
+        Request::Add { reference, key } => match Identity::from_str(&key) {
+            Ok(identity) => {
+                keys.insert(
+                    reference,
+                    Entry {
+                        identity,
+                        expires: now + ttl,
+                    },
+                );
+                Response::Added
+            }
+            Err(e) => Response::Error {
+                message: format!("Invalid Age key: {}", e),
+            },
+        },
+        Request::Lock => {
+            let forgotten = keys.len();
+            keys.clear();
+            Response::Locked { forgotten }
+        }
+        Request::Status => {
+            let mut cached: Vec<CachedKey> = keys
+                .iter()
+                .map(|(reference, entry)| CachedKey {
+                    reference: reference.clone(),
+                    public_key: entry.identity.to_public().to_string(),
+                    expires_in_seconds: entry.expires.duration_since(now).as_secs(),
+                })
+                .collect();
+            cached.sort_by(|a, b| a.reference.cmp(&b.reference));
+            Response::Status {
+                ttl_seconds: ttl.as_secs(),
+                keys: cached,
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use crate::util::sops_decrypt::decrypt_file;
+use colored::Colorize;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Entry point for the `cat` command: streams the plaintext of a file to stdout.
+/// Nothing but the plaintext is written to stdout so the output can be piped.
+pub fn cat(path: OsString) {
+    let path = Path::new(&path);
+
+    // Check if the file exists
+    if !path.is_file() {
+        eprintln!("{} {}", "❌ File not found:".red(), path.display());
+        std::process::exit(1);
+    }
+
+    let plaintext = match decrypt_file(path) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Error while decrypting the file:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut stdout = io::stdout().lock();
+    if let Err(e) = stdout.write_all(&plaintext).and_then(|_| stdout.flush()) {
+        // A closed pipe (e.g. `opsops cat file | head`) is not an error worth reporting
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("{} {}", "❌ Failed to write to stdout:".red(), e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,466 @@
+use crate::util::find_project_root::find_project_root;
+use crate::util::op::split_reference;
+use crate::util::sops_structs::{CreationRule, SopsConfig, TeamMember};
+use crate::util::yaml_tree::{YamlKind, YamlNode, line_column, parse_yaml};
+use age::x25519::Recipient;
+use colored::Colorize;
+use regex::Regex;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// `path_regex` values that match every file, like a rule without one
+const CATCH_ALL_PATTERNS: [&str; 5] = ["", ".*", "^.*", ".*$", "^.*$"];
+
+/// Entry point for `config validate`: reports every problem in .sops.yaml with its line and
+/// column, and exits with 1 if there are any so it can run in CI
+pub fn config_validate() {
+    let Some(path) = find_project_root().map(|root| root.join(".sops.yaml")) else {
+        eprintln!("{}", "❌ Could not determine project root.".red());
+        std::process::exit(1);
+    };
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("{} {}: {}", "❌ Failed to read".red(), path.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut problems = validate(&text);
+    if problems.is_empty() {
+        println!("{}", "✅ .sops.yaml is valid".green());
+        return;
+    }
+
+    problems.sort_by_key(|p| p.offset);
+    for problem in &problems {
+        let (line, column) = line_column(&text, problem.offset);
+        eprintln!(
+            "{} {} {}",
+            "❌".red(),
+            format!(".sops.yaml:{}:{}:", line, column).bold(),
+            problem.message
+        );
+    }
+    eprintln!(
+        "{}",
+        format!("Found {} problem(s) in .sops.yaml", problems.len()).red()
+    );
+    std::process::exit(1);
+}
+
+struct Problem {
+    /// Byte offset into .sops.yaml
+    offset: usize,
+    message: String,
+}
+
+fn validate(text: &str) -> Vec<Problem> {
+    let tree = match parse_yaml(text) {
+        Ok(tree) => tree,
+        Err(e) => {
+            return vec![Problem {
+                offset: e.offset,
+                message: format!("invalid YAML: {}", e),
+            }];
+        }
+    };
+
+    let mut validator = Validator {
+        text,
+        config: &SopsConfig::default(),
+        problems: Vec::new(),
+        shadowed: Vec::new(),
+    };
+    validator.duplicate_keys(&tree);
+
+    // serde_yaml refuses duplicate keys, so the other checks read the file without the
+    // entries a later one overrides. Blanking them keeps every offset where it was.
+    let mut readable = text.to_string();
+    for range in &validator.shadowed {
+        let blank: String = text[range.clone()]
+            .chars()
+            .map(|c| match c {
+                '\n' => "\n".to_string(),
+                c => " ".repeat(c.len_utf8()),
+            })
+            .collect();
+        readable.replace_range(range.clone(), &blank);
+    }
+
+    let config = match serde_yaml::from_str::<SopsConfig>(&readable) {
+        Ok(config) => config,
+        Err(e) => {
+            let message = e.to_string();
+            let message = message.split(" at line ").next().unwrap_or(&message);
+            validator.problems.push(Problem {
+                offset: e.location().map_or(0, |l| l.index()),
+                message: message.to_string(),
+            });
+            return validator.problems;
+        }
+    };
+
+    validator.config = &config;
+    validator.top_level(&tree);
+    validator.problems
+}
+
+struct Validator<'a> {
+    text: &'a str,
+    config: &'a SopsConfig,
+    problems: Vec<Problem>,
+    /// Entries overridden by a later one with the same key
+    shadowed: Vec<Range<usize>>,
+}
+
+impl Validator<'_> {
+    fn report(&mut self, node: Option<&YamlNode>, message: String) {
+        self.problems.push(Problem {
+            offset: node.map_or(0, |n| n.start),
+            message,
+        });
+    }
+
+    fn duplicate_keys(&mut self, node: &YamlNode) {
+        match &node.kind {
+            YamlKind::Mapping(entries) => {
+                let mut seen = HashMap::new();
+                for (key, value) in entries {
+                    if let Some(name) = key.scalar()
+                        && let Some(earlier) = seen.insert(name, key.start..value.end)
+                    {
+                        self.report(Some(key), format!("duplicate key `{}`", name));
+                        self.shadowed.push(earlier);
+                    }
+                    self.duplicate_keys(value);
+                }
+            }
+            YamlKind::Sequence(items) => {
+                for item in items {
+                    self.duplicate_keys(item);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn top_level(&mut self, tree: &YamlNode) {
+        let config = self.config;
+        self.unknown_fields(Some(tree), &config.other, "the top level");
+
+        if !config.onepassworditem.is_empty() {
+            self.reference(tree.get("onepassworditem"), &config.onepassworditem);
+        }
+        for (name, reference) in &config.onepassworditems {
+            let node = tree.get("onepassworditems").and_then(|n| n.get(name));
+            self.reference(node, reference);
+        }
+        for (name, member) in &config.recipients {
+            self.team_member(tree.get("recipients").and_then(|n| n.get(name)), member);
+        }
+
+        let rules = tree.get("creation_rules");
+        let mut catch_all = None;
+        for (i, rule) in config.creation_rules.iter().enumerate() {
+            let node = rules.and_then(|n| n.item(i));
+            if let Some(first) = catch_all {
+                self.report(
+                    node,
+                    format!(
+                        "creation rule #{} is never used, rule #{} before it matches every file",
+                        i + 1,
+                        first + 1
+                    ),
+                );
+            }
+            self.rule(node, rule, &format!("creation rule #{}", i + 1));
+
+            let pattern = rule.path_regex.as_deref().unwrap_or("");
+            if catch_all.is_none() && CATCH_ALL_PATTERNS.contains(&pattern) {
+                catch_all = Some(i);
+            }
+        }
+
+        let destinations = tree.get("destination_rules");
+        for (i, destination) in config.destination_rules.iter().enumerate() {
+            let node = destinations.and_then(|n| n.item(i));
+            let what = format!("destination rule #{}", i + 1);
+            self.regex(node, "path_regex", &destination.path_regex);
+            self.unknown_fields(node, &destination.other, &what);
+            if let Some(rule) = &destination.recreation_rule {
+                let node = node.and_then(|n| n.get("recreation_rule"));
+                self.rule(node, rule, &format!("the recreation rule of {}", what));
+            }
+        }
+
+        if let Some(stores) = &config.stores {
+            let node = tree.get("stores");
+            self.unknown_fields(node, &stores.other, "stores");
+            let formats = [
+                ("yaml", &stores.yaml),
+                ("json", &stores.json),
+                ("json_binary", &stores.json_binary),
+                ("dotenv", &stores.dotenv),
+                ("ini", &stores.ini),
+            ];
+            for (name, store) in formats {
+                if let Some(store) = store {
+                    let what = format!("stores.{}", name);
+                    self.unknown_fields(node.and_then(|n| n.get(name)), &store.other, &what);
+                }
+            }
+        }
+    }
+
+    fn rule(&mut self, node: Option<&YamlNode>, rule: &CreationRule, what: &str) {
+        let get = |key: &str| node.and_then(|n| n.get(key));
+        self.unknown_fields(node, &rule.other, what);
+
+        let regexes = [
+            ("path_regex", &rule.path_regex),
+            ("encrypted_regex", &rule.encrypted_regex),
+            ("unencrypted_regex", &rule.unencrypted_regex),
+            ("encrypted_comment_regex", &rule.encrypted_comment_regex),
+            ("unencrypted_comment_regex", &rule.unencrypted_comment_regex),
+        ];
+        for (field, pattern) in regexes {
+            self.regex(node, field, pattern);
+        }
+
+        for entry in rule.age.iter().flat_map(|age| age.keys()) {
+            self.age_recipient(get("age"), &entry);
+        }
+
+        let groups = get("key_groups");
+        for (g, group) in rule.key_groups.iter().enumerate() {
+            let node = groups.and_then(|n| n.item(g));
+            let what = format!("key group #{} of {}", g + 1, what);
+            let empty = group.age.is_empty()
+                && group.pgp.is_empty()
+                && group.kms.is_empty()
+                && group.gcp_kms.is_empty()
+                && group.azure_keyvault.is_empty()
+                && group.hc_vault.is_empty();
+            if empty {
+                self.report(node, format!("{} has no keys", what));
+            }
+
+            for (i, entry) in group.age.iter().enumerate() {
+                let entry_node = node.and_then(|n| n.get("age")).and_then(|n| n.item(i));
+                self.age_recipient(entry_node, entry.trim());
+            }
+
+            self.unknown_fields(node, &group.other, &what);
+            let others = group
+                .kms
+                .iter()
+                .map(|k| ("kms", &k.other))
+                .enumerate()
+                .chain(
+                    group
+                        .gcp_kms
+                        .iter()
+                        .map(|k| ("gcp_kms", &k.other))
+                        .enumerate(),
+                )
+                .chain(
+                    group
+                        .azure_keyvault
+                        .iter()
+                        .map(|k| ("azure_keyvault", &k.other))
+                        .enumerate(),
+                );
+            for (i, (key_type, other)) in others {
+                let key_node = node.and_then(|n| n.get(key_type)).and_then(|n| n.item(i));
+                self.unknown_fields(key_node, other, &format!("a {} key of {}", key_type, what));
+            }
+        }
+
+        if let Some(threshold) = rule.shamir_threshold {
+            let groups = rule.key_groups.len().max(1);
+            if threshold as usize > groups {
+                self.report(
+                    get("shamir_threshold"),
+                    format!(
+                        "shamir_threshold is {} but {} has only {} key group(s)",
+                        threshold, what, groups
+                    ),
+                );
+            }
+        }
+
+        if let Some(item) = &rule.onepassworditem
+            && !self.config.onepassworditems.contains_key(item)
+        {
+            self.reference(get("onepassworditem"), item);
+        }
+    }
+
+    fn team_member(&mut self, node: Option<&YamlNode>, member: &TeamMember) {
+        let (key_node, item_node) = match node {
+            Some(n) if matches!(n.kind, YamlKind::Mapping(_)) => {
+                (n.get("key"), n.get("onepassworditem"))
+            }
+            _ => (node, None),
+        };
+
+        if let Err(e) = parse_age_recipient(member.key()) {
+            self.report(
+                key_node,
+                format!("malformed age recipient `{}`: {}", member.key(), e),
+            );
+        }
+        if let Some(item) = member.onepassworditem() {
+            self.reference(item_node, item);
+        }
+
+        // Members are untagged, so serde can't tell us about keys it skipped
+        if let Some(YamlKind::Mapping(entries)) = node.map(|n| &n.kind) {
+            for (key, _) in entries {
+                if let Some(name) = key.scalar()
+                    && !["key", "onepassworditem"].contains(&name)
+                {
+                    self.report(Some(key), format!("unknown field `{}` in recipients", name));
+                }
+            }
+        }
+    }
+
+    fn regex(&mut self, node: Option<&YamlNode>, field: &str, pattern: &Option<String>) {
+        let Some(pattern) = pattern else {
+            return;
+        };
+        if let Err(e) = Regex::new(pattern) {
+            // The last line of regex' error is the explanation, the others draw the pattern
+            let e = e.to_string();
+            let reason = e
+                .lines()
+                .last()
+                .unwrap_or_default()
+                .trim_start_matches("error: ");
+            self.report(
+                node.and_then(|n| n.get(field)),
+                format!("invalid regex in {}: {}", field, reason),
+            );
+        }
+    }
+
+    /// A rule entry is a name from `recipients` or a public key
+    fn age_recipient(&mut self, node: Option<&YamlNode>, entry: &str) {
+        if self.config.recipients.contains_key(entry) {
+            return;
+        }
+        let Err(e) = parse_age_recipient(entry) else {
+            return;
+        };
+
+        // Point at the key itself inside a comma separated list
+        let offset = node.map_or(0, |n| {
+            n.start + self.text[n.start..n.end].find(entry).unwrap_or(0)
+        });
+        self.problems.push(Problem {
+            offset,
+            message: format!(
+                "malformed age recipient `{}`: {} (and not a name from recipients)",
+                entry, e
+            ),
+        });
+    }
+
+    fn reference(&mut self, node: Option<&YamlNode>, reference: &str) {
+        if let Err(e) = split_reference(reference) {
+            self.report(node, e);
+        }
+    }
+
+    fn unknown_fields(&mut self, node: Option<&YamlNode>, other: &Mapping, what: &str) {
+        for key in other.keys() {
+            let name = match key {
+                Value::String(s) => s.clone(),
+                key => serde_yaml::to_string(key)
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+            };
+            let key_node = node.and_then(|n| n.entry(&name)).map(|(key, _)| key);
+            self.report(
+                key_node.or(node),
+                format!("unknown field `{}` in {}", name, what),
+            );
+        }
+    }
+}
+
+/// sops takes X25519 recipients as well as SSH public keys
+fn parse_age_recipient(key: &str) -> Result<(), String> {
+    if key.starts_with("ssh-ed25519 ") || key.starts_with("ssh-rsa ") {
+        return Ok(());
+    }
+    Recipient::from_str(key)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(text: &str) -> Vec<String> {
+        validate(text).into_iter().map(|p| p.message).collect()
+    }
+
+    #[test]
+    fn keeps_checking_after_a_duplicate_key() {
+        let text = "creation_rules:\n  - path_regex: ^prod/\n    age: nope\n    path_regex: ^dev/(\nonepassworditem: op://V/i/f\nonepassworditem: not-a-reference\n";
+        let messages = messages(text);
+        assert!(
+            messages.iter().any(|m| m == "duplicate key `path_regex`"),
+            "{:?}",
+            messages
+        );
+        assert!(
+            messages
+                .iter()
+                .any(|m| m == "duplicate key `onepassworditem`"),
+            "{:?}",
+            messages
+        );
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.starts_with("invalid regex in path_regex")),
+            "{:?}",
+            messages
+        );
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.starts_with("malformed age recipient `nope`")),
+            "{:?}",
+            messages
+        );
+        assert_eq!(messages.len(), 5, "{:?}", messages);
+    }
+
+    #[test]
+    fn reports_problems_at_their_position() {
+        let text = "creation_rules:\n  - age: é\n    age: nope\n";
+        let problems = validate(text);
+        assert!(problems.iter().any(|p| p.message == "duplicate key `age`"));
+        let malformed = problems
+            .iter()
+            .find(|p| p.message.starts_with("malformed age recipient"))
+            .unwrap();
+        assert_eq!(line_column(text, malformed.offset), (3, 10));
+    }
+
+    #[test]
+    fn accepts_a_valid_config() {
+        let text = "onepassworditem: op://V/i/Private Key\ncreation_rules:\n  - path_regex: ^prod/\n    age: age13a4en57fuq3skac33qumj524ga5v4gllr7qewfw257e3yreehdgq8k754w\n";
+        assert!(messages(text).is_empty(), "{:?}", messages(text));
+    }
+}
@@ -1,90 +1,169 @@
-use crate::util::sops_command::SopsCommandBuilder;
-use crate::util::sops_status::is_file_unchanged_status;
+use crate::commands::cat::cat;
+use crate::util::bulk::{BulkResult, BulkStatus, is_up_to_date, print_summary, sync_modified};
+use crate::util::output_path::{check_overwrite, decrypted_path, replace_file, write_private_file};
+use crate::util::project_files::ProjectFiles;
+use crate::util::sops_config::read_or_create_config;
+use crate::util::sops_decrypt::decrypt_file;
 use colored::Colorize;
 use std::ffi::OsString;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-/// Decrypts a file using SOPS with the Age key from 1Password
-pub fn decrypt(path: OsString) {
-    // Convert the path from OsString to String
-    let path_str = match path.into_string() {
-        Ok(p) => p,
-        Err(os) => {
-            eprintln!("{} {:?}", "❌ Invalid UTF-8 in path:".red(), os);
-            std::process::exit(1);
-        }
-    };
+/// Decrypts a file with the Age key from 1Password, natively when possible.
+/// The plaintext goes next to the encrypted file (`foo.enc.yaml` -> `foo.yaml`) unless
+/// `output` or `in_place` say otherwise, and existing files are only replaced with `force`.
+/// With `all`, every encrypted file in the project that matches a creation rule is decrypted.
+pub fn decrypt(
+    path: Option<OsString>,
+    stdout: bool,
+    output: Option<OsString>,
+    in_place: bool,
+    force: bool,
+    all: bool,
+) {
+    if all {
+        decrypt_all(in_place, force);
+        return;
+    }
 
-    // Check if the file exists
-    if !Path::new(&path_str).is_file() {
-        eprintln!("{} {}", "❌ File not found:".red(), path_str);
+    let Some(path) = path else {
+        eprintln!("{}", "❌ No file given to decrypt.".red());
         std::process::exit(1);
+    };
+
+    // Streaming to stdout never writes plaintext to disk
+    if stdout {
+        cat(path);
+        return;
     }
 
-    // Ensure sops is installed
-    if which::which("sops").is_err() {
-        eprintln!(
-            "{} {}",
-            "❌ 'sops' is not installed or not in PATH.".red(),
-            "Please install it first.".dimmed()
-        );
+    let path = PathBuf::from(path);
+
+    // Check if the file exists
+    if !path.is_file() {
+        eprintln!("{} {}", "❌ File not found:".red(), path.display());
         std::process::exit(1);
     }
 
-    // Create the decrypted output path - remove .enc extension if it exists, otherwise add .dec
-    let output_path = if path_str.ends_with(".enc") {
-        path_str[..path_str.len() - 4].to_string()
+    let output_path = if in_place {
+        path.clone()
+    } else if let Some(output) = output {
+        PathBuf::from(output)
     } else {
-        format!("{}", path_str)
+        let markers = read_or_create_config()
+            .map(|c| c.encrypted_markers())
+            .unwrap_or_default();
+        match decrypted_path(&path, &markers) {
+            Some(p) => p,
+            None => {
+                eprintln!(
+                    "{} {} {}",
+                    "❌ Can't derive a plaintext file name for".red(),
+                    path.display(),
+                    "(expected a name like foo.enc.yaml). Use --output or --in-place.".red()
+                );
+                std::process::exit(1);
+            }
+        }
     };
 
+    if !in_place && let Err(e) = check_overwrite(&output_path, force) {
+        eprintln!("{} {}", "❌".red(), e);
+        std::process::exit(1);
+    }
+
     println!(
         "{} {} {} {}",
         "🔓 Decrypting".green(),
-        path_str,
+        path.display(),
         "to".green(),
-        output_path
+        output_path.display()
+    );
+
+    if let Err(e) = decrypt_to(&path, &output_path) {
+        eprintln!("{} {}", "❌ Error while decrypting the file:".red(), e);
+        std::process::exit(1);
+    }
+
+    println!(
+        "{} {}",
+        "✅ Successfully decrypted file to".green(),
+        output_path.display()
     );
+}
+
+/// Decrypts `path` and writes the plaintext to `output_path`. An existing file is replaced in
+/// one step, a new one is only readable by its owner.
+fn decrypt_to(path: &Path, output_path: &Path) -> Result<(), String> {
+    let plaintext = decrypt_file(path)?;
+    if output_path.exists() {
+        replace_file(output_path, &plaintext)
+    } else {
+        write_private_file(output_path, &plaintext)
+    }
+}
 
-    // Create a SOPS command with the Age key from 1Password
-    let sops_command = match SopsCommandBuilder::new()
-        .arg("--decrypt")
-        .arg("--output")
-        .arg(&output_path)
-        .arg(&path_str)
-        .with_age_key()
-    {
-        Ok(cmd) => cmd,
+/// Decrypts every encrypted file in the project that matches a creation rule.
+/// Plaintext copies that are newer than their encrypted file are left alone unless forced.
+fn decrypt_all(in_place: bool, force: bool) {
+    let config = match read_or_create_config() {
+        Ok(c) => c,
         Err(e) => {
-            eprintln!("{} {}", "❌ Failed to get Age key:".red(), e);
+            eprintln!("{} {}", "❌ Failed to read SOPS config:".red(), e);
             std::process::exit(1);
         }
     };
 
-    // Run the command
-    match sops_command.status() {
-        Ok(status) if status.success() => {
-            println!(
-                "{} {} {}",
-                "✅ Successfully decrypted file to".green(),
-                output_path,
-                "with SOPS".green()
-            );
-        }
-        Ok(status) if is_file_unchanged_status(&status) => {
-            println!("{} {}", "ℹ️ File has not changed.".blue(), output_path);
-        }
-        Ok(status) => {
-            eprintln!(
-                "{} Exit code: {}",
-                "❌ Error while decrypting the file.".red(),
-                status
-            );
-            std::process::exit(status.code().unwrap_or(1));
-        }
+    let project = match ProjectFiles::discover() {
+        Ok(p) => p,
         Err(e) => {
-            eprintln!("{} {:?}", "❌ Failed to launch sops:".red(), e);
+            eprintln!("{} {}", "❌".red(), e);
             std::process::exit(1);
         }
+    };
+
+    println!(
+        "{} {}",
+        "🔓 Decrypting secrets in".green(),
+        project.root().display()
+    );
+
+    let markers = config.encrypted_markers();
+    let mut results = Vec::new();
+    for file in project.secret_files(&config) {
+        if !file.encrypted {
+            continue;
+        }
+        let path = file.path;
+
+        let output_path = if in_place {
+            Some(path.clone())
+        } else {
+            decrypted_path(&path, &markers)
+        };
+
+        let status = match output_path {
+            None => {
+                BulkStatus::Failed("no plaintext name can be derived, use --in-place".to_string())
+            }
+            Some(output_path) if !in_place && !force && is_up_to_date(&path, &output_path) => {
+                BulkStatus::UpToDate
+            }
+            Some(output_path) => match decrypt_to(&path, &output_path) {
+                Ok(()) if in_place => BulkStatus::Changed(String::new()),
+                Ok(()) if sync_modified(&path, &output_path).is_err() => BulkStatus::Failed(
+                    "failed to update the modification time of the plaintext".to_string(),
+                ),
+                Ok(()) => BulkStatus::Changed(format!("-> {}", project.display_path(&output_path))),
+                Err(e) => BulkStatus::Failed(e),
+            },
+        };
+        results.push(BulkResult {
+            file: project.display_path(&path),
+            status,
+        });
+    }
+
+    if print_summary("decrypted", &results) {
+        std::process::exit(1);
     }
 }
@@ -1,10 +1,11 @@
-use crate::util::{op_key::get_age_key_from_1password, sops_config::read_or_create_config};
-use age::{
-    secrecy::{ExposeSecret, ExposeSecretMut, SecretString},
-    x25519::{Identity, Recipient},
+use crate::util::{
+    op_key::{age_identity, read_age_key},
+    sops_config::read_or_create_config,
+    sops_document::SopsFormat,
+    sops_structs::SopsConfig,
 };
+use age::secrecy::ExposeSecret;
 use colored::Colorize;
-use std::str::FromStr;
 
 pub fn doctor() {
     let config = match read_or_create_config() {
@@ -14,22 +15,76 @@ pub fn doctor() {
             return;
         }
     };
-    // Check if onepassworditem is set
-    if config.onepassworditem.is_empty() {
+    // Check if any 1Password reference is set
+    let references = config.onepassword_references();
+    if references.is_empty() {
         eprint!(
             "{}",
             "❌ No 1Password reference found in .sops.yaml. Run 'opsops init' to configure.".red()
         );
         return;
-    } else {
-        print!(
-            "{} {}\n",
-            "✅ 1Password reference found in .sops.yaml:".green(),
-            config.onepassworditem
-        );
     }
 
-    let age = match get_age_key_from_1password() {
+    for reference in &references {
+        check_reference(&config, reference);
+    }
+
+    check_rules(&config);
+}
+
+/// Check that sops is around for the rules opsops can't encrypt for natively, and that the
+/// key groups of every rule can actually be decrypted
+fn check_rules(config: &SopsConfig) {
+    let sops_installed = which::which("sops").is_ok();
+    let stores = [SopsFormat::Yaml, SopsFormat::Json, SopsFormat::Binary]
+        .into_iter()
+        .filter_map(|format| config.store_requires_sops(format));
+
+    let reasons = config
+        .creation_rules
+        .iter()
+        .enumerate()
+        .filter_map(|(i, rule)| {
+            rule.requires_sops()
+                .map(|reason| format!("Rule #{}: {}", i + 1, reason))
+        })
+        .chain(stores);
+    for reason in reasons {
+        if sops_installed {
+            println!("{} {}", "ℹ️ Encrypted with sops:".blue(), reason);
+        } else {
+            eprintln!(
+                "{} {}",
+                "❌ Needs sops, which is not installed:".red(),
+                reason
+            );
+        }
+    }
+
+    for (i, rule) in config.creation_rules.iter().enumerate() {
+        if let Some(threshold) = rule.shamir_threshold
+            && threshold as usize > rule.key_groups.len().max(1)
+        {
+            eprintln!(
+                "{} rule #{} has a shamir_threshold of {} but only {} key group(s)",
+                "❌".red(),
+                i + 1,
+                threshold,
+                rule.key_groups.len().max(1)
+            );
+        }
+    }
+}
+
+/// Check that a 1Password reference holds an age key whose public key is used in .sops.yaml
+fn check_reference(config: &SopsConfig, reference: &str) {
+    println!(
+        "{} {}",
+        "✅ 1Password reference found in .sops.yaml:".green(),
+        reference
+    );
+
+    let age = match read_age_key(reference) {
         Ok(it) => it,
         Err(err) => {
             eprintln!("{} {}", "❌ Couldn't get age key:".red(), err);
@@ -37,16 +92,19 @@ pub fn doctor() {
         }
     };
 
-    // Create a copy of age before moving it
-    let age_copy = age.clone();
-    let mut hiddenkey = age_copy;
-    let stars = "*".repeat(hiddenkey.len() - 22);
-    hiddenkey.replace_range(15..=(hiddenkey.len() - 8), &stars);
-    print!("{} {}\n", "✅ Got private key:".green(), hiddenkey);
+    // Only the prefix and the end of the key are shown, nothing is copied out of the secret
+    let key = age.expose_secret();
+    let hidden = key.len().saturating_sub(22);
+    println!(
+        "{} {}{}{}",
+        "✅ Got private key:".green(),
+        &key[..15.min(key.len())],
+        "*".repeat(hidden),
+        &key[(15 + hidden).min(key.len())..]
+    );
 
     // Parse the private key into an Identity
-    let secret_key = SecretString::from(age);
-    let identity = match Identity::from_str(secret_key.expose_secret()) {
+    let identity = match age_identity(&age) {
         Ok(id) => id,
         Err(err) => {
             eprintln!("{} {}", "❌ Invalid private key format:".red(), err);
@@ -58,53 +116,31 @@ pub fn doctor() {
     let recipient = identity.to_public();
     let derived_public_key = recipient.to_string();
 
-    // Get public keys from config
+    // Get public keys from config, with names from the team directory resolved
     let mut found = false;
     let mut rules_without_age = Vec::new();
 
-    // Check single keys in creation rules and collect rules without age keys
+    // Check the recipients of every creation rule and collect rules without age keys
     for (i, rule) in config.creation_rules.iter().enumerate() {
-        let mut rule_has_keys = false;
-
-        // Check direct age key
-        if let Some(key) = &rule.age {
-            rule_has_keys = true;
-            if derived_public_key == *key {
-                print!("{} {}\n", "✅ Found matching public key:".green(), key);
-                found = true;
-                break;
-            }
-        }
-
-        // Check key groups
-        let mut has_key_in_groups = false;
-        for key_group in &rule.key_groups {
-            if !key_group.age.is_empty() {
-                rule_has_keys = true;
-                for key in &key_group.age {
-                    if derived_public_key == *key {
-                        print!(
-                            "{} {}\n",
-                            "✅ Found matching public key in key group:".green(),
-                            key
-                        );
-                        found = true;
-                        break;
-                    }
-                }
-            }
-            if found {
-                break;
-            }
-        }
-
-        // If this rule has no age keys at all, record it
-        if !rule_has_keys {
+        let recipients = config.rule_recipients(rule);
+        if recipients.is_empty() {
             rules_without_age.push(i);
+            continue;
         }
 
-        if found {
-            break;
+        if !found && recipients.contains(&derived_public_key) {
+            let label = config.recipient_label(&derived_public_key);
+            if label == derived_public_key {
+                println!("{} {}", "✅ Found matching public key:".green(), label);
+            } else {
+                println!(
+                    "{} {} ({})",
+                    "✅ Found matching public key:".green(),
+                    label,
+                    derived_public_key
+                );
+            }
+            found = true;
         }
     }
 
@@ -1,85 +1,233 @@
+use crate::util::bulk::{BulkResult, BulkStatus, is_up_to_date, print_summary, sync_modified};
+use crate::util::output_path::{
+    check_overwrite, encrypted_path, has_encrypted_marker, replace_file, write_private_file,
+};
+use crate::util::project_files::ProjectFiles;
 use crate::util::sops_command::SopsCommandBuilder;
+use crate::util::sops_config::{find_creation_rule, read_or_create_config};
+use crate::util::sops_document::{SopsDocument, SopsFormat, SopsMetadata, parse_plaintext};
 use crate::util::sops_status::is_file_unchanged_status;
+use crate::util::sops_structs::SopsConfig;
 use colored::Colorize;
 use std::ffi::OsString;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-/// Encrypts a file using SOPS with the Age key from 1Password
-pub fn encrypt(path: OsString) {
-    // Convert the path from OsString to String
-    let path_str = match path.into_string() {
-        Ok(p) => p,
-        Err(os) => {
-            eprintln!("{} {:?}", "❌ Invalid UTF-8 in path:".red(), os);
+/// Encrypts a file for the recipients of its creation rule, natively when possible.
+/// The result goes next to the plaintext (`foo.yaml` -> `foo.enc.yaml`) unless `output` or
+/// `in_place` say otherwise, and existing files are only replaced with `force`.
+/// With `all`, every file in the project that matches a creation rule is encrypted.
+pub fn encrypt(
+    path: Option<OsString>,
+    output: Option<OsString>,
+    in_place: bool,
+    force: bool,
+    all: bool,
+) {
+    let config = match read_or_create_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to read SOPS config:".red(), e);
             std::process::exit(1);
         }
     };
 
+    if all {
+        encrypt_all(&config, in_place, force);
+        return;
+    }
+
+    let Some(path) = path else {
+        eprintln!("{}", "❌ No file given to encrypt.".red());
+        std::process::exit(1);
+    };
+    let path = PathBuf::from(path);
+
     // Check if the file exists
-    if !Path::new(&path_str).is_file() {
-        eprintln!("{} {}", "❌ File not found:".red(), path_str);
+    if !path.is_file() {
+        eprintln!("{} {}", "❌ File not found:".red(), path.display());
         std::process::exit(1);
     }
 
-    // Ensure sops is installed
-    if which::which("sops").is_err() {
-        eprintln!(
-            "{} {}",
-            "❌ 'sops' is not installed or not in PATH.".red(),
-            "Please install it first.".dimmed()
-        );
+    // A file that already carries an encrypted name (foo.enc.yaml) is encrypted in place
+    let markers = config.encrypted_markers();
+    let in_place = in_place || (output.is_none() && has_encrypted_marker(&path, &markers));
+    let output_path = match (in_place, output) {
+        (true, _) => path.clone(),
+        (false, Some(output)) => PathBuf::from(output),
+        (false, None) => encrypted_path(&path, &markers).unwrap_or_else(|| path.clone()),
+    };
+
+    if !in_place && let Err(e) = check_overwrite(&output_path, force) {
+        eprintln!("{} {}", "❌".red(), e);
         std::process::exit(1);
     }
 
-    let output_path = format!("{}", path_str);
-
     println!(
         "{} {} {} {}",
         "🔐 Encrypting".green(),
-        path_str,
+        path.display(),
         "to".green(),
-        output_path
+        output_path.display()
     );
 
-    // Create a SOPS command with the Age key from 1Password
-    let sops_command = match SopsCommandBuilder::new()
-        .arg("--encrypt")
-        .arg("--output")
-        .arg(&output_path)
-        .arg(&path_str)
-        .with_age_key()
-    {
-        Ok(cmd) => cmd,
+    match encrypt_file(&config, &path, &output_path) {
+        Ok(true) => println!(
+            "{} {}",
+            "✅ Successfully encrypted file to".green(),
+            output_path.display()
+        ),
+        Ok(false) => println!(
+            "{} {}",
+            "ℹ️ File is already encrypted:".blue(),
+            path.display()
+        ),
         Err(e) => {
-            eprintln!("{} {}", "❌ Failed to get Age key:".red(), e);
+            eprintln!("{} {}", "❌ Error while encrypting the file:".red(), e);
             std::process::exit(1);
         }
+    }
+}
+
+/// Encrypts `path` into `output_path` for the creation rule matching `output_path`.
+/// Returns false if there was nothing to do because the file is already encrypted.
+pub fn encrypt_file(config: &SopsConfig, path: &Path, output_path: &Path) -> Result<bool, String> {
+    // Only formats opsops understands are encrypted in-process, everything else goes to sops
+    let Some(format) = SopsFormat::from_path(path) else {
+        return encrypt_with_sops(path, output_path);
     };
 
-    // Run the command
-    match sops_command.status() {
-        Ok(status) if status.success() => {
-            println!(
-                "{} {} {}",
-                "✅ Successfully encrypted file to".green(),
-                output_path,
-                "with SOPS".green()
-            );
-        }
-        Ok(status) if is_file_unchanged_status(&status) => {
-            println!("{} {}", "ℹ️ File has not changed.".blue(), output_path);
-        }
-        Ok(status) => {
-            eprintln!(
-                "{} Exit code: {}",
-                "❌ Error while encrypting the file.".red(),
-                status
-            );
-            std::process::exit(status.code().unwrap_or(1));
+    // Like sops' --filename-override, the rule is chosen by the name of the encrypted file
+    let rule = match find_creation_rule(config, output_path)? {
+        Some((_, rule)) => rule,
+        None => {
+            return Err(format!(
+                "No creation rule in .sops.yaml matches {}",
+                output_path.display()
+            ));
         }
+    };
+
+    if let Some(reason) = rule
+        .requires_sops()
+        .or_else(|| config.store_requires_sops(format))
+    {
+        eprintln!("{} {}", "ℹ️ Falling back to sops:".blue(), reason);
+        return encrypt_with_sops(path, output_path);
+    }
+
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read file: {}", e))?;
+
+    if SopsDocument::parse(&contents, format).is_ok() {
+        return Ok(false);
+    }
+
+    let encrypted = parse_plaintext(&contents, format)
+        .and_then(|tree| {
+            SopsDocument::encrypt(
+                tree,
+                format,
+                &config.rule_recipients(rule),
+                SopsMetadata::for_rule(rule),
+            )
+        })
+        .and_then(|document| document.emit(config.store_indent(format)))?;
+
+    if output_path.exists() {
+        replace_file(output_path, encrypted.as_bytes())?;
+    } else {
+        write_private_file(output_path, encrypted.as_bytes())?;
+    }
+    Ok(true)
+}
+
+/// Encrypts a file by shelling out to the sops binary
+fn encrypt_with_sops(path: &Path, output_path: &Path) -> Result<bool, String> {
+    // Ensure sops is installed
+    if which::which("sops").is_err() {
+        return Err("'sops' is not installed or not in PATH. Please install it first.".to_string());
+    }
+
+    // Create a SOPS command with the Age key from 1Password
+    let status = SopsCommandBuilder::new()
+        .arg("--encrypt")
+        .arg("--filename-override")
+        .arg(output_path)
+        .arg("--output")
+        .arg(output_path)
+        .arg(path)
+        .with_age_key(output_path)
+        .map_err(|e| format!("Failed to get Age key: {}", e))?
+        .status()
+        .map_err(|e| format!("Failed to launch sops: {}", e))?;
+
+    match status {
+        s if s.success() => Ok(true),
+        s if is_file_unchanged_status(&s) => Ok(false),
+        s => Err(format!("sops exited with {}", s)),
+    }
+}
+
+/// Encrypts every plaintext file in the project that matches a creation rule
+fn encrypt_all(config: &SopsConfig, in_place: bool, force: bool) {
+    let project = match ProjectFiles::discover() {
+        Ok(p) => p,
         Err(e) => {
-            eprintln!("{} {:?}", "❌ Failed to launch sops:".red(), e);
+            eprintln!("{} {}", "❌".red(), e);
             std::process::exit(1);
         }
+    };
+
+    println!(
+        "{} {}",
+        "🔐 Encrypting secrets in".green(),
+        project.root().display()
+    );
+
+    let markers = config.encrypted_markers();
+    let mut results = Vec::new();
+    for file in project.secret_files(config) {
+        let path = file.path;
+
+        // Files named like foo.enc.yaml are encrypted in place, others get an encrypted copy
+        let marked = has_encrypted_marker(&path, &markers);
+        let output_path = if in_place || marked {
+            path.clone()
+        } else {
+            match encrypted_path(&path, &markers) {
+                Some(p) => p,
+                None => continue,
+            }
+        };
+
+        // Encrypted copies show up as well, they are the output rather than the input
+        if file.encrypted && (marked || !in_place) {
+            continue;
+        }
+
+        let status =
+            if file.encrypted || (!force && !in_place && is_up_to_date(&path, &output_path)) {
+                BulkStatus::UpToDate
+            } else {
+                match encrypt_file(config, &path, &output_path) {
+                    Ok(true) if output_path == path => BulkStatus::Changed(String::new()),
+                    Ok(true) if sync_modified(&path, &output_path).is_err() => BulkStatus::Failed(
+                        "failed to update the modification time of the encrypted file".to_string(),
+                    ),
+                    Ok(true) => {
+                        BulkStatus::Changed(format!("-> {}", project.display_path(&output_path)))
+                    }
+                    Ok(false) => BulkStatus::UpToDate,
+                    Err(e) => BulkStatus::Failed(e),
+                }
+            };
+        results.push(BulkResult {
+            file: project.display_path(&path),
+            status,
+        });
+    }
+
+    if print_summary("encrypted", &results) {
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,161 @@
+use crate::util::sops_decrypt::decrypt_file;
+use colored::Colorize;
+use serde_yaml::Value;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+/// Entry point for the `exec` command: runs a command with every top-level key of the
+/// decrypted file exported as an environment variable, like `sops exec-env`.
+pub fn exec(path: OsString, command: Vec<OsString>, prefix: Option<String>, flatten: bool) {
+    let path = Path::new(&path);
+
+    // Check if the file exists
+    if !path.is_file() {
+        eprintln!("{} {}", "❌ File not found:".red(), path.display());
+        std::process::exit(1);
+    }
+
+    let plaintext = match decrypt_file(path) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Error while decrypting the file:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let vars = match secrets_to_env(path, &plaintext, prefix.as_deref().unwrap_or(""), flatten) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to read secrets:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let Some((program, args)) = command.split_first() else {
+        eprintln!("{}", "❌ No command given to run.".red());
+        std::process::exit(1);
+    };
+
+    let mut child = Command::new(program);
+    child.args(args).envs(vars);
+    run(child, program);
+}
+
+/// Replace opsops with the child so signals and the exit code go straight to it
+#[cfg(unix)]
+fn run(mut child: Command, program: &OsString) {
+    use std::os::unix::process::CommandExt;
+
+    let err = child.exec();
+    eprintln!("{} {:?}: {}", "❌ Failed to run".red(), program, err);
+    std::process::exit(127);
+}
+
+#[cfg(not(unix))]
+fn run(mut child: Command, program: &OsString) {
+    match child.status() {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(err) => {
+            eprintln!("{} {:?}: {}", "❌ Failed to run".red(), program, err);
+            std::process::exit(127);
+        }
+    }
+}
+
+/// Turn the plaintext of a dotenv, YAML or JSON file into environment variables
+fn secrets_to_env(
+    path: &Path,
+    plaintext: &[u8],
+    prefix: &str,
+    flatten: bool,
+) -> Result<Vec<(String, String)>, String> {
+    let text = std::str::from_utf8(plaintext).map_err(|_| "File is not valid UTF-8".to_string())?;
+
+    let mut vars = Vec::new();
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("env") => {
+            for line in text.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (key, value) = line
+                    .split_once('=')
+                    .ok_or_else(|| format!("Invalid dotenv line: {}", line))?;
+                vars.push((format!("{}{}", prefix, key), value.to_string()));
+            }
+        }
+        Some("yaml") | Some("yml") | Some("json") => {
+            let tree: Value = serde_yaml::from_str(text)
+                .map_err(|e| format!("Failed to parse decrypted file: {}", e))?;
+            let Value::Mapping(mapping) = tree else {
+                return Err("The top level of the file must be a mapping".to_string());
+            };
+            for (key, value) in &mapping {
+                collect_vars(
+                    &format!("{}{}", prefix, scalar_to_string(key)),
+                    value,
+                    flatten,
+                    &mut vars,
+                )?;
+            }
+        }
+        _ => return Err("Only dotenv, YAML and JSON files can be exported".to_string()),
+    }
+
+    for (name, _) in &vars {
+        if name.is_empty() || name.contains('=') || name.contains('\0') {
+            return Err(format!(
+                "'{}' is not a valid environment variable name",
+                name
+            ));
+        }
+    }
+
+    Ok(vars)
+}
+
+fn collect_vars(
+    name: &str,
+    value: &Value,
+    flatten: bool,
+    vars: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    match value {
+        Value::Mapping(mapping) if flatten => {
+            for (key, child) in mapping {
+                collect_vars(
+                    &format!("{}_{}", name, scalar_to_string(key)),
+                    child,
+                    flatten,
+                    vars,
+                )?;
+            }
+        }
+        Value::Sequence(items) if flatten => {
+            for (i, child) in items.iter().enumerate() {
+                collect_vars(&format!("{}_{}", name, i), child, flatten, vars)?;
+            }
+        }
+        Value::Mapping(_) | Value::Sequence(_) => {
+            // Without flattening, nested structures are passed as JSON
+            let json = serde_json::to_string(value)
+                .map_err(|e| format!("Failed to serialize '{}': {}", name, e))?;
+            vars.push((name.to_string(), json));
+        }
+        Value::Tagged(tagged) => collect_vars(name, &tagged.value, flatten, vars)?,
+        _ => vars.push((name.to_string(), scalar_to_string(value))),
+    }
+    Ok(())
+}
+
+fn scalar_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Null => String::new(),
+        other => serde_json::to_string(other).unwrap_or_default(),
+    }
+}
@@ -0,0 +1,207 @@
+use crate::util::output_path::write_private_file;
+use crate::util::sops_decrypt::decrypt_file;
+use colored::Colorize;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+
+/// Placeholder in the command that is replaced with the path of the plaintext file
+const PLACEHOLDER: &str = "{}";
+
+/// Entry point for the `exec-file` command: materializes the decrypted file in a private
+/// temporary directory, runs the command with `{}` replaced by its path and removes it afterwards.
+pub fn exec_file(path: OsString, command: Vec<OsString>, fifo: bool, filename: Option<String>) {
+    let path = Path::new(&path);
+
+    // Check if the file exists
+    if !path.is_file() {
+        eprintln!("{} {}", "❌ File not found:".red(), path.display());
+        std::process::exit(1);
+    }
+
+    let file_name = match filename {
+        Some(name) => match plain_file_name(&name) {
+            Ok(name) => name,
+            Err(e) => {
+                eprintln!("{} {}", "❌".red(), e);
+                std::process::exit(1);
+            }
+        },
+        None => path
+            .file_name()
+            .map(|n| n.to_os_string())
+            .unwrap_or_else(|| OsString::from("secrets")),
+    };
+
+    let plaintext = match decrypt_file(path) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Error while decrypting the file:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    // The directory is private to the user and removed with everything in it when dropped
+    let dir = match tempfile::Builder::new()
+        .prefix("opsops-")
+        .permissions(Permissions::from_mode(0o700))
+        .tempdir()
+    {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to create temporary directory:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let secret_path = dir.path().join(file_name);
+
+    let prepared = if fifo {
+        make_fifo(&secret_path).map_err(|e| e.to_string())
+    } else {
+        write_private_file(&secret_path, &plaintext)
+    };
+    if let Err(e) = prepared {
+        eprintln!("{} {}", "❌ Failed to create plaintext file:".red(), e);
+        std::process::exit(1);
+    }
+
+    // Handle signals ourselves so the plaintext is removed even if opsops is interrupted
+    let mut signals = match Signals::new([SIGINT, SIGTERM, SIGHUP, SIGQUIT]) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to install signal handlers:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let args = substitute_placeholder(&command, &secret_path);
+    let Some((program, args)) = args.split_first() else {
+        eprintln!("{}", "❌ No command given to run.".red());
+        std::process::exit(1);
+    };
+
+    let mut child = match Command::new(program).args(args).spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{} {:?}: {}", "❌ Failed to run".red(), program, e);
+            drop(dir);
+            std::process::exit(127);
+        }
+    };
+
+    if fifo {
+        // Opening a FIFO for writing blocks until the child opens it for reading
+        let fifo_path = secret_path.clone();
+        thread::spawn(move || {
+            if let Ok(mut pipe) = OpenOptions::new().write(true).open(&fifo_path) {
+                let _ = pipe.write_all(&plaintext);
+            }
+        });
+    }
+
+    // Terminal signals already reach the child through the process group, others are forwarded
+    let child_pid = child.id() as libc::pid_t;
+    let handle = signals.handle();
+    let forwarder = thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal != SIGINT && signal != SIGQUIT {
+                // SAFETY: kill takes no pointers and has no memory safety preconditions. A
+                // signal in the moment between reaping the child and closing the handle goes
+                // to a pid that is free, or at worst reused.
+                unsafe {
+                    libc::kill(child_pid, signal);
+                }
+            }
+        }
+    });
+
+    let status = child.wait();
+
+    handle.close();
+    let _ = forwarder.join();
+
+    if let Err(e) = dir.close() {
+        eprintln!("{} {}", "⚠️  Failed to remove plaintext file:".yellow(), e);
+    }
+
+    match status {
+        Ok(status) => std::process::exit(
+            status
+                .code()
+                .or_else(|| status.signal().map(|s| 128 + s))
+                .unwrap_or(1),
+        ),
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to wait for command:".red(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Replace every `{}` in the command with the plaintext path, or append it if there is none
+fn substitute_placeholder(command: &[OsString], secret_path: &Path) -> Vec<OsString> {
+    let replacement = secret_path.to_string_lossy();
+    let mut found = false;
+
+    let mut args: Vec<OsString> = command
+        .iter()
+        .map(|arg| match arg.to_str() {
+            Some(s) if s.contains(PLACEHOLDER) => {
+                found = true;
+                OsString::from(s.replace(PLACEHOLDER, &replacement))
+            }
+            _ => arg.clone(),
+        })
+        .collect();
+
+    if !found && !args.is_empty() {
+        args.push(secret_path.as_os_str().to_os_string());
+    }
+    args
+}
+
+/// `--filename` must name a file inside the private directory, not a path leading out of it
+fn plain_file_name(name: &str) -> Result<OsString, String> {
+    if Path::new(name).file_name() == Some(OsStr::new(name)) {
+        Ok(OsString::from(name))
+    } else {
+        Err(format!(
+            "--filename has to be a plain file name, without '/' and not '.' or '..': {}",
+            name
+        ))
+    }
+}
+
+/// Create a named pipe only accessible by the current user
+fn make_fifo(path: &Path) -> std::io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `c_path` is a valid nul-terminated string that outlives the call
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filename_must_stay_in_the_private_directory() {
+        assert_eq!(plain_file_name("app.env"), Ok(OsString::from("app.env")));
+        assert_eq!(plain_file_name(".env"), Ok(OsString::from(".env")));
+        for name in ["../x", "/abs/path", "dir/x", ".", "..", "", "x/"] {
+            assert!(plain_file_name(name).is_err(), "{} was accepted", name);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use age::{secrecy::ExposeSecret, x25519};
 use colored::Colorize;
 use dialoguer::{Confirm, Input, theme::ColorfulTheme};
+use zeroize::Zeroizing;
 
 use crate::util::op::{OpCategory, OpItem, OpItemField, op_item_create};
 
@@ -18,26 +19,36 @@ pub fn generate_age_key() {
         pubkey.to_string().cyan()
     );
 
-    println!(
-        "{} {}",
-        format!("{:width$}", "🔐 Private Key:", width = label_width)
-            .red()
-            .bold(),
-        key.to_string().expose_secret()
-    );
-
-    if Confirm::with_theme(&ColorfulTheme::default())
+    let saved = if Confirm::with_theme(&ColorfulTheme::default())
         .with_prompt("Would you like to save this key in 1Password?")
         .default(false)
         .interact()
         .unwrap()
     {
-        let name = Input::with_theme(&ColorfulTheme::default())
+        let name: String = Input::with_theme(&ColorfulTheme::default())
             .with_prompt("Choose a name for the 1Password item")
             .interact_text()
             .unwrap();
-        save_to_op(&key, name);
+        match save_to_op(&key, "Personal", &name) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("{} {}", "❌".red(), e);
+                false
+            }
+        }
     } else {
+        false
+    };
+
+    // A key stored in 1Password never has to be shown
+    if !saved {
+        println!(
+            "{} {}",
+            format!("{:width$}", "🔐 Private Key:", width = label_width)
+                .red()
+                .bold(),
+            key.to_string().expose_secret()
+        );
         println!(
             "{}",
             "Remember to save this key in a secure location!".dimmed()
@@ -45,9 +56,11 @@ pub fn generate_age_key() {
     }
 }
 
-fn save_to_op(key: &x25519::Identity, item_name: String) {
+/// Store a key pair in 1Password, the private key in a "Private Key" and the public key in a
+/// "Public Key" field
+pub fn save_to_op(key: &x25519::Identity, vault: &str, item_name: &str) -> Result<(), String> {
     let item = OpItem {
-        vault: "Personal".to_string(),
+        vault: vault.to_string(),
         title: item_name.to_string(),
         category: OpCategory::Password,
         fields: vec![
@@ -55,16 +68,16 @@ fn save_to_op(key: &x25519::Identity, item_name: String) {
                 section: None,
                 field: "Public Key".to_string(),
                 field_type: Some("STRING".to_string()),
-                value: key.to_public().to_string(),
+                value: Zeroizing::new(key.to_public().to_string()),
             },
             OpItemField {
                 section: None,
                 field: "Private Key".to_string(),
-                field_type: Some("PASSWORD".to_string()),
-                value: key.to_string().expose_secret().to_string(),
+                field_type: Some("CONCEALED".to_string()),
+                value: Zeroizing::new(key.to_string().expose_secret().to_string()),
             },
         ],
     };
 
-    op_item_create(item);
+    op_item_create(item)
 }
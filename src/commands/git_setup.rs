@@ -0,0 +1,107 @@
+use crate::util::find_project_root::find_project_root;
+use crate::util::gitattributes::{regex_to_globs, write_gitattributes_block};
+use crate::util::sops_config::read_or_create_config;
+use colored::Colorize;
+use git2::{ConfigLevel, Repository};
+
+/// Names of the drivers referenced from `.gitattributes`
+const DIFF_DRIVER: &str = "sopsdiffer";
+const MERGE_DRIVER: &str = "sopsmerge";
+
+/// Entry point for the `git-setup` command: registers `opsops cat` as a textconv diff driver
+/// and `opsops merge-driver` as merge driver, and marks every file matching a creation rule
+/// with both, so `git diff` shows plaintext and merges happen on the decrypted values.
+pub fn git_setup() {
+    let Some(project_root) = find_project_root() else {
+        eprintln!("{}", "❌ Could not find the project root.".red());
+        std::process::exit(1);
+    };
+
+    let repo = match Repository::open(&project_root) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!(
+                "{} {}",
+                "❌ The project root is not a git repository:".red(),
+                e.message()
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let config = match read_or_create_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to read SOPS config:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    // The drivers go into the repository's own config, .git/config
+    let settings = [
+        (format!("diff.{}.textconv", DIFF_DRIVER), "opsops cat"),
+        (
+            format!("merge.{}.name", MERGE_DRIVER),
+            "opsops merge of decrypted sops files",
+        ),
+        (
+            format!("merge.{}.driver", MERGE_DRIVER),
+            "opsops merge-driver %O %A %B %P",
+        ),
+    ];
+    let configured = repo
+        .config()
+        .and_then(|c| c.open_level(ConfigLevel::Local))
+        .and_then(|mut c| {
+            settings
+                .iter()
+                .try_for_each(|(name, value)| c.set_str(name, value))
+        });
+    if let Err(e) = configured {
+        eprintln!("{} {}", "❌ Failed to configure git:".red(), e.message());
+        std::process::exit(1);
+    }
+    for (name, value) in &settings {
+        println!("{} {} = {}", "✅ Configured".green(), name, value);
+    }
+
+    let mut lines = Vec::new();
+    for (i, rule) in config.creation_rules.iter().enumerate() {
+        // A catch-all rule would send every file in the repository through opsops
+        let Some(pattern) = rule.path_regex.as_deref().filter(|p| !p.is_empty()) else {
+            continue;
+        };
+
+        match regex_to_globs(pattern) {
+            Some(globs) => {
+                lines.extend(
+                    globs
+                        .iter()
+                        .map(|g| format!("{} diff={} merge={}", g, DIFF_DRIVER, MERGE_DRIVER)),
+                );
+            }
+            None => println!(
+                "{} rule #{} ({}) has no gitattributes equivalent, add it to .gitattributes yourself",
+                "⚠️  Skipping".yellow(),
+                i + 1,
+                pattern
+            ),
+        }
+    }
+
+    let gitattributes = project_root.join(".gitattributes");
+    if let Err(e) = write_gitattributes_block(&gitattributes, &lines) {
+        eprintln!("{} {}", "❌".red(), e);
+        std::process::exit(1);
+    }
+
+    println!(
+        "{} {} {}",
+        "✅ Wrote".green(),
+        lines.len(),
+        format!("entries to {}", gitattributes.display()).green()
+    );
+    for line in &lines {
+        println!("   {}", line);
+    }
+}
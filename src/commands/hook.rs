@@ -0,0 +1,278 @@
+use crate::util::output_path::{encrypted_path, has_encrypted_marker};
+use crate::util::sops_config::{matches_explicit_rule, read_or_create_config};
+use crate::util::sops_document::is_encrypted_contents;
+use colored::Colorize;
+use git2::{Delta, Index, Repository};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Marks hooks written by opsops so they can be replaced without --force
+const HOOK_MARKER: &str = "# Installed by opsops";
+
+/// Entry point for `hook install`: writes a pre-commit hook that runs `opsops hook run`
+pub fn hook_install(force: bool) {
+    let repo = match Repository::discover(".") {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!(
+                "{} {}",
+                "❌ Not inside a git repository:".red(),
+                e.message()
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let hook_path = hooks_dir(&repo).join("pre-commit");
+
+    // Never silently replace a hook somebody else wrote
+    if let Ok(existing) = fs::read_to_string(&hook_path)
+        && !existing.contains(HOOK_MARKER)
+        && !force
+    {
+        eprintln!(
+            "{} {} {}",
+            "❌ A pre-commit hook already exists at".red(),
+            hook_path.display(),
+            "(use --force to replace it)".red()
+        );
+        std::process::exit(1);
+    }
+
+    let script = format!(
+        "#!/bin/sh\n{}: refuses to commit plaintext secrets\nexec opsops hook run\n",
+        HOOK_MARKER
+    );
+
+    let written = hook_path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&hook_path, script))
+        .and_then(|_| fs::set_permissions(&hook_path, fs::Permissions::from_mode(0o755)));
+    if let Err(e) = written {
+        eprintln!("{} {}", "❌ Failed to write pre-commit hook:".red(), e);
+        std::process::exit(1);
+    }
+
+    println!(
+        "{} {}",
+        "✅ Installed pre-commit hook at".green(),
+        hook_path.display()
+    );
+}
+
+/// Entry point for `hook run`: rejects the commit if a staged file matches a creation rule, or
+/// is the plaintext of a file that does, but isn't encrypted with sops. Only the staged blobs
+/// are checked, not the working tree.
+pub fn hook_run() {
+    let repo = match Repository::discover(".") {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!(
+                "{} {}",
+                "❌ Not inside a git repository:".red(),
+                e.message()
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let Some(workdir) = repo.workdir().map(|p| p.to_path_buf()) else {
+        // Nothing is committed from a bare repository
+        return;
+    };
+
+    let config = match read_or_create_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to read SOPS config:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let index = match repo.index() {
+        Ok(index) => index,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to read the index:".red(), e.message());
+            std::process::exit(1);
+        }
+    };
+    let markers = config.encrypted_markers();
+    let matches_rule = |path: &Path| matches_explicit_rule(&config, &workdir.join(path));
+
+    let plaintext = match staged_plaintext(&repo, |path| {
+        should_be_encrypted(path, &markers, &workdir, &index, matches_rule)
+    }) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to inspect staged files:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    if plaintext.is_empty() {
+        return;
+    }
+
+    eprintln!(
+        "{}",
+        "❌ Refusing to commit files that should be encrypted with sops:".red()
+    );
+    for path in &plaintext {
+        eprintln!("   {}", path.display());
+    }
+    eprintln!();
+    eprintln!(
+        "{}",
+        "Encrypt them with `opsops encrypt <file>` or unstage them with `git rm --cached <file>`."
+            .dimmed()
+    );
+    std::process::exit(1);
+}
+
+/// Returns true if a staged file (relative to the work tree) has to be encrypted: it matches a
+/// creation rule, or its encrypted name does or is in the repository. The latter catches the
+/// `db.yaml` that `opsops decrypt db.enc.yaml` writes, when only `*.enc.yaml` match a rule.
+fn should_be_encrypted<F>(
+    path: &Path,
+    markers: &[String],
+    workdir: &Path,
+    index: &Index,
+    matches_rule: F,
+) -> bool
+where
+    F: Fn(&Path) -> bool,
+{
+    if matches_rule(path) {
+        return true;
+    }
+    if has_encrypted_marker(path, markers) {
+        return false;
+    }
+    encrypted_path(path, markers).is_some_and(|encrypted| {
+        matches_rule(&encrypted)
+            || index.get_path(&encrypted, 0).is_some()
+            || workdir.join(&encrypted).exists()
+    })
+}
+
+/// Staged files that have to be encrypted but whose staged blob isn't a sops document
+fn staged_plaintext<F>(repo: &Repository, should_be_encrypted: F) -> Result<Vec<PathBuf>, String>
+where
+    F: Fn(&Path) -> bool,
+{
+    // Before the first commit everything in the index is staged
+    let head = repo.head().ok().and_then(|h| h.peel_to_tree().ok());
+    let diff = repo
+        .diff_tree_to_index(head.as_ref(), None, None)
+        .map_err(|e| e.message().to_string())?;
+
+    let mut plaintext = Vec::new();
+    for delta in diff.deltas() {
+        if !matches!(
+            delta.status(),
+            Delta::Added | Delta::Modified | Delta::Renamed | Delta::Copied | Delta::Typechange
+        ) {
+            continue;
+        }
+
+        let file = delta.new_file();
+        let Some(path) = file.path().map(|p| p.to_path_buf()) else {
+            continue;
+        };
+        if !should_be_encrypted(&path) {
+            continue;
+        }
+
+        let blob = repo
+            .find_blob(file.id())
+            .map_err(|e| format!("{}: {}", path.display(), e.message()))?;
+        let encrypted = std::str::from_utf8(blob.content())
+            .is_ok_and(|contents| is_encrypted_contents(&path, contents));
+        if !encrypted {
+            plaintext.push(path);
+        }
+    }
+
+    Ok(plaintext)
+}
+
+/// The hooks directory, honouring `core.hooksPath`
+fn hooks_dir(repo: &Repository) -> PathBuf {
+    let configured = repo
+        .config()
+        .ok()
+        .and_then(|c| c.get_path("core.hooksPath").ok());
+
+    match (configured, repo.workdir()) {
+        (Some(path), Some(workdir)) if path.is_relative() => workdir.join(path),
+        (Some(path), _) => path,
+        (None, _) => repo.path().join("hooks"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A repository with `files` written to the work tree and staged
+    fn repo_with_staged(files: &[(&str, &str)]) -> (tempfile::TempDir, Repository) {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let mut index = repo.index().unwrap();
+        for (name, contents) in files {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            index.add_path(Path::new(name)).unwrap();
+        }
+        index.write().unwrap();
+        (dir, repo)
+    }
+
+    fn plaintext_in(repo: &Repository, matches_rule: fn(&Path) -> bool) -> Vec<PathBuf> {
+        let markers = vec!["enc".to_string(), "sops".to_string()];
+        let workdir = repo.workdir().unwrap().to_path_buf();
+        let index = repo.index().unwrap();
+        staged_plaintext(repo, |path| {
+            should_be_encrypted(path, &markers, &workdir, &index, matches_rule)
+        })
+        .unwrap()
+    }
+
+    fn enc_yaml_rule(path: &Path) -> bool {
+        path.to_string_lossy().ends_with(".enc.yaml")
+    }
+
+    #[test]
+    fn blocks_plaintext_matching_a_rule() {
+        let (_dir, repo) = repo_with_staged(&[("db.enc.yaml", "password: hunter2\n")]);
+        assert_eq!(
+            plaintext_in(&repo, enc_yaml_rule),
+            [Path::new("db.enc.yaml")]
+        );
+    }
+
+    #[test]
+    fn blocks_decrypted_copy_whose_encrypted_name_matches_a_rule() {
+        let (_dir, repo) = repo_with_staged(&[("db.yaml", "password: hunter2\n")]);
+        assert_eq!(plaintext_in(&repo, enc_yaml_rule), [Path::new("db.yaml")]);
+    }
+
+    #[test]
+    fn blocks_decrypted_copy_next_to_its_encrypted_file() {
+        let (dir, repo) = repo_with_staged(&[("secrets/api.yaml", "token: abc\n")]);
+        fs::write(dir.path().join("secrets/api.enc.yaml"), "").unwrap();
+        assert_eq!(
+            plaintext_in(&repo, |_| false),
+            [Path::new("secrets/api.yaml")]
+        );
+    }
+
+    #[test]
+    fn allows_unrelated_files() {
+        let (_dir, repo) = repo_with_staged(&[("README.md", "# hi\n"), ("app.json", "{}\n")]);
+        assert!(plaintext_in(&repo, enc_yaml_rule).is_empty());
+    }
+}
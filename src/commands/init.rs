@@ -26,7 +26,7 @@ pub fn init() {
                 return;
             }
 
-            let config: SopsConfig = match from_str(&contents) {
+            let _config: SopsConfig = match from_str(&contents) {
                 Ok(c) => c,
                 Err(e) => {
                     eprintln!("{} {}", "❌ Failed to parse YAML:".red(), e);
@@ -50,10 +50,9 @@ pub fn init() {
                 let config = SopsConfig {
                     creation_rules: vec![CreationRule {
                         path_regex: Some(".*".to_string()),
-                        age: None,
-                        key_groups: Vec::new(),
+                        ..Default::default()
                     }],
-                    onepassworditem: String::new(),
+                    ..Default::default()
                 };
 
                 if let Err(e) = write_config(&config) {
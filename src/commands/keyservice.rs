@@ -0,0 +1,248 @@
+use crate::util::keyservice::{
+    DecryptRequest, DecryptResponse, EncryptRequest, EncryptResponse, KeyService, KeyServiceServer,
+};
+use crate::util::op_key::{age_identity, find_age_key};
+use crate::util::private_socket::{bind_private_socket, peer_is_user, socket_dir};
+use crate::util::secret_memory::disable_core_dumps;
+use crate::util::sops_config::read_or_create_config;
+use crate::util::sops_document::{unwrap_data_key, wrap_data_key_for};
+use age::secrecy::SecretString;
+use colored::Colorize;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::{Request, Response, Status};
+
+/// Entry point for `keyservice`: serves the sops KeyService on a Unix socket, so sops can
+/// encrypt and decrypt data keys for age recipients without ever seeing the private key
+pub fn keyservice(socket: Option<OsString>, items: Vec<String>) {
+    let config = match read_or_create_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to read SOPS config:".red(), e);
+            std::process::exit(1);
+        }
+    };
+    let references: Vec<String> = if items.is_empty() {
+        config.onepassword_references()
+    } else {
+        items
+            .into_iter()
+            .map(|item| config.onepassworditems.get(&item).cloned().unwrap_or(item))
+            .collect()
+    };
+    if references.is_empty() {
+        eprintln!(
+            "{}",
+            "❌ No 1Password reference found in .sops.yaml, use --item to choose a key.".red()
+        );
+        std::process::exit(1);
+    }
+
+    let path = socket
+        .map(PathBuf::from)
+        .unwrap_or_else(|| socket_dir().join("keyservice.sock"));
+    let listener = match bind_private_socket(&path, "A keyservice").and_then(|l| {
+        l.set_nonblocking(true)
+            .map(|_| l)
+            .map_err(|e| e.to_string())
+    }) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("{} {}", "❌".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    disable_core_dumps();
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to start the keyservice:".red(), e);
+            let _ = fs::remove_file(&path);
+            std::process::exit(1);
+        }
+    };
+
+    println!(
+        "{} unix://{}",
+        "🔐 opsops keyservice listening on".green(),
+        path.display()
+    );
+    println!(
+        "{}",
+        format!(
+            "Use it with `sops --keyservice unix://{} --enable-local-keyservice=false ...`",
+            path.display()
+        )
+        .dimmed()
+    );
+
+    let result = runtime.block_on(serve(listener, references));
+    let _ = fs::remove_file(&path);
+    match result {
+        Ok(()) => println!("{}", "🔒 Keyservice stopped".green()),
+        Err(e) => {
+            eprintln!("{} {}", "❌ Keyservice failed:".red(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn serve(
+    listener: std::os::unix::net::UnixListener,
+    references: Vec<String>,
+) -> Result<(), String> {
+    let listener = tokio::net::UnixListener::from_std(listener).map_err(|e| e.to_string())?;
+    let incoming = UnixListenerStream::new(listener)
+        .filter(|connection| connection.as_ref().map_or(true, peer_is_user));
+
+    let mut terminate = signal(SignalKind::terminate()).map_err(|e| e.to_string())?;
+    let shutdown = async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    };
+
+    tonic::transport::Server::builder()
+        .add_service(KeyServiceServer::new(OpKeyService {
+            references,
+            find_key: find_age_key,
+        }))
+        .serve_with_incoming_shutdown(incoming, shutdown)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Wraps data keys for any age recipient, and unwraps them with the configured key in
+/// 1Password (or the agent) whose public key is the recipient
+struct OpKeyService {
+    references: Vec<String>,
+    /// Finds the key for a recipient among the references, `find_age_key` outside of tests
+    find_key: fn(&[String], &[String]) -> Result<SecretString, String>,
+}
+
+#[tonic::async_trait]
+impl KeyService for OpKeyService {
+    async fn encrypt(
+        &self,
+        request: Request<EncryptRequest>,
+    ) -> Result<Response<EncryptResponse>, Status> {
+        let request = request.into_inner();
+        let recipient = request
+            .age_recipient()
+            .ok_or_else(|| Status::unimplemented("opsops only serves age keys"))?;
+
+        let ciphertext =
+            wrap_data_key_for(&request.plaintext, recipient).map_err(Status::invalid_argument)?;
+        log_request("🔒 Encrypted a data key for", recipient);
+        Ok(Response::new(EncryptResponse {
+            ciphertext: ciphertext.into_bytes(),
+        }))
+    }
+
+    async fn decrypt(
+        &self,
+        request: Request<DecryptRequest>,
+    ) -> Result<Response<DecryptResponse>, Status> {
+        let request = request.into_inner();
+        let recipient = request
+            .age_recipient()
+            .ok_or_else(|| Status::unimplemented("opsops only serves age keys"))?
+            .to_string();
+        let ciphertext = String::from_utf8(request.ciphertext)
+            .map_err(|_| Status::invalid_argument("the age payload isn't armored"))?;
+
+        // `op` runs as a child process and may wait for the user to approve it
+        let references = self.references.clone();
+        let key_recipient = recipient.clone();
+        let find_key = self.find_key;
+        let identity = tokio::task::spawn_blocking(move || {
+            let key = find_key(&references, &[key_recipient])?;
+            age_identity(&key)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(Status::permission_denied)?;
+        let plaintext =
+            unwrap_data_key(&ciphertext, &identity).map_err(Status::invalid_argument)?;
+
+        log_request("🔓 Decrypted a data key for", &recipient);
+        Ok(Response::new(DecryptResponse {
+            plaintext: plaintext.to_vec(),
+        }))
+    }
+}
+
+fn log_request(what: &str, recipient: &str) {
+    eprintln!("{} {}", what.dimmed(), recipient.dimmed());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::keyservice::{AgeKey, Key, KeyType};
+    use tonic::codec::ProstCodec;
+    use tonic::codegen::http::uri::PathAndQuery;
+
+    const KNOWN_KEY: &str = include_str!("../../tests/fixtures/known.agekey");
+
+    fn known_key(_: &[String], _: &[String]) -> Result<SecretString, String> {
+        Ok(SecretString::from(KNOWN_KEY.trim()))
+    }
+
+    fn age_key(recipient: &str) -> Option<Key> {
+        Some(Key {
+            key_type: Some(KeyType::AgeKey(AgeKey {
+                recipient: recipient.to_string(),
+            })),
+        })
+    }
+
+    /// Calls the service like sops does, through the gRPC paths of keyservice.proto
+    #[tokio::test]
+    async fn data_keys_round_trip_through_the_service() {
+        let recipient = age_identity(&known_key(&[], &[]).unwrap())
+            .unwrap()
+            .to_public()
+            .to_string();
+        let service = KeyServiceServer::new(OpKeyService {
+            references: Vec::new(),
+            find_key: known_key,
+        });
+        let mut client = tonic::client::Grpc::new(service);
+        let data_key = vec![42u8; 32];
+
+        client.ready().await.unwrap();
+        let encrypted: Response<EncryptResponse> = client
+            .unary(
+                Request::new(EncryptRequest {
+                    key: age_key(&recipient),
+                    plaintext: data_key.clone(),
+                }),
+                PathAndQuery::from_static("/KeyService/Encrypt"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap();
+
+        client.ready().await.unwrap();
+        let decrypted: Response<DecryptResponse> = client
+            .unary(
+                Request::new(DecryptRequest {
+                    key: age_key(&recipient),
+                    ciphertext: encrypted.into_inner().ciphertext,
+                }),
+                PathAndQuery::from_static("/KeyService/Decrypt"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(decrypted.into_inner().plaintext, data_key);
+    }
+}
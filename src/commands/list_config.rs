@@ -2,7 +2,10 @@ use colored::*;
 use serde_yaml::from_str;
 use std::io::Read;
 
-use crate::util::{sops_config::get_sops_config, sops_structs::SopsConfig};
+use crate::util::{
+    sops_config::get_sops_config,
+    sops_structs::{CreationRule, SopsConfig},
+};
 
 pub fn list_config() {
     let mut file = match get_sops_config() {
@@ -27,38 +30,71 @@ pub fn list_config() {
         }
     };
 
-    print!(
-        "{} {}\n",
+    println!(
+        "{} {}",
         "Assigned 1Password item:".cyan(),
         config.onepassworditem.green()
     );
+    for (name, reference) in &config.onepassworditems {
+        println!("  {} {}", format!("{}:", name).cyan(), reference.green());
+    }
+    if !config.recipients.is_empty() {
+        println!("{}", "Recipients:".cyan());
+        for (name, member) in &config.recipients {
+            match member.onepassworditem() {
+                Some(item) => println!(
+                    "  {} {} {}",
+                    format!("{}:", name).cyan(),
+                    member.key().green(),
+                    format!("({})", item).dimmed()
+                ),
+                None => println!("  {} {}", format!("{}:", name).cyan(), member.key().green()),
+            }
+        }
+    }
     print!("{}", "Rules:".cyan());
 
     for (i, rule) in config.creation_rules.iter().enumerate() {
         println!();
         println!("{} {}", "🔹 Rule #".yellow(), (i + 1).to_string().yellow());
+        print_rule(&config, rule, "  ");
+    }
 
-        if let Some(pattern) = &rule.path_regex {
+    for (i, destination) in config.destination_rules.iter().enumerate() {
+        println!();
+        println!(
+            "{} {}",
+            "📤 Destination rule #".yellow(),
+            (i + 1).to_string().yellow()
+        );
+        if let Some(pattern) = &destination.path_regex {
             println!("{} {}", "  📂 File pattern:".cyan(), pattern.green());
         }
-
-        if !rule.key_groups.is_empty() {
-            let mut any_age = false;
-            for group in &rule.key_groups {
-                if !group.age.is_empty() {
-                    if !any_age {
-                        println!("{}", "  🔑 Age Keys:".cyan());
-                        any_age = true;
-                    }
-                    for key in &group.age {
-                        println!("    - {}", key.green());
-                    }
-                }
-            }
+        println!(
+            "{} {}",
+            "  🌐 Destination:".cyan(),
+            destination.destination().green()
+        );
+        if let Some(rule) = &destination.recreation_rule {
+            println!("{}", "  ♻️ Re-encrypted for:".cyan());
+            print_rule(&config, rule, "    ");
         }
+    }
 
-        if let Some(age_key) = &rule.age {
-            println!("{} {}", "  🔑 Age Key:".cyan(), age_key.green());
+    if let Some(stores) = &config.stores {
+        println!();
+        println!("{}", "Stores:".cyan());
+        let stores = [
+            ("yaml", &stores.yaml),
+            ("json", &stores.json),
+            ("json_binary", &stores.json_binary),
+            ("dotenv", &stores.dotenv),
+            ("ini", &stores.ini),
+        ];
+        for (name, store) in stores {
+            if let Some(indent) = store.as_ref().and_then(|s| s.indent) {
+                println!("  {} indent {}", format!("{}:", name).cyan(), indent);
+            }
         }
     }
 
@@ -68,3 +104,126 @@ pub fn list_config() {
         "💡 This configuration will be used when encrypting files with SOPS.".dimmed()
     );
 }
+
+pub fn print_rule(config: &SopsConfig, rule: &CreationRule, indent: &str) {
+    if let Some(pattern) = &rule.path_regex {
+        println!(
+            "{} {}",
+            format!("{}📂 File pattern:", indent).cyan(),
+            pattern.green()
+        );
+    }
+
+    let numbered = rule.key_groups.len() > 1;
+    for (i, group) in rule.key_groups.iter().enumerate() {
+        let indent = if numbered {
+            println!("{}", format!("{}🔑 Key group #{}:", indent, i + 1).cyan());
+            format!("{}  ", indent)
+        } else {
+            indent.to_string()
+        };
+        let age: Vec<String> = group
+            .age
+            .iter()
+            .map(|key| config.recipient_label(key))
+            .collect();
+        let kms: Vec<String> = group.kms.iter().map(|k| k.arn.clone()).collect();
+        let gcp_kms: Vec<String> = group
+            .gcp_kms
+            .iter()
+            .map(|k| k.resource_id.clone())
+            .collect();
+        let azure_keyvault: Vec<String> = group
+            .azure_keyvault
+            .iter()
+            .map(|k| format!("{}/keys/{}/{}", k.vault_url, k.key, k.version))
+            .collect();
+
+        print_key_list(&indent, "Age Keys", &age);
+        print_key_list(&indent, "PGP Keys", &group.pgp);
+        print_key_list(&indent, "KMS Keys", &kms);
+        print_key_list(&indent, "GCP KMS Keys", &gcp_kms);
+        print_key_list(&indent, "Azure Key Vault Keys", &azure_keyvault);
+        print_key_list(&indent, "Vault Transit Keys", &group.hc_vault);
+    }
+    if let Some(threshold) = rule.shamir_threshold {
+        println!(
+            "{} {}",
+            format!("{}🧩 Shamir threshold:", indent).cyan(),
+            threshold.to_string().green()
+        );
+    }
+
+    let keys = [
+        ("Age Key", &rule.age),
+        ("PGP Key", &rule.pgp),
+        ("KMS Key", &rule.kms),
+        ("GCP KMS Key", &rule.gcp_kms),
+        ("Azure Key Vault Key", &rule.azure_keyvault),
+        ("Vault Transit Key", &rule.hc_vault_transit_uri),
+    ];
+    for (label, list) in keys {
+        if let Some(list) = list {
+            let labels: Vec<String> = list
+                .keys()
+                .iter()
+                .map(|key| config.recipient_label(key))
+                .collect();
+            println!(
+                "{} {}",
+                format!("{}🔑 {}:", indent, label).cyan(),
+                labels.join(", ").green()
+            );
+        }
+    }
+    if let Some(profile) = &rule.aws_profile {
+        println!(
+            "{} {}",
+            format!("{}👤 AWS profile:", indent).cyan(),
+            profile.green()
+        );
+    }
+
+    let settings = [
+        ("encrypted_suffix", &rule.encrypted_suffix),
+        ("unencrypted_suffix", &rule.unencrypted_suffix),
+        ("encrypted_regex", &rule.encrypted_regex),
+        ("unencrypted_regex", &rule.unencrypted_regex),
+        ("encrypted_comment_regex", &rule.encrypted_comment_regex),
+        ("unencrypted_comment_regex", &rule.unencrypted_comment_regex),
+    ];
+    for (name, value) in settings {
+        if let Some(value) = value {
+            println!(
+                "{} {}",
+                format!("{}🔒 {}:", indent, name).cyan(),
+                value.green()
+            );
+        }
+    }
+    if rule.mac_only_encrypted == Some(true) {
+        println!(
+            "{} {}",
+            format!("{}🔒 mac_only_encrypted:", indent).cyan(),
+            "true".green()
+        );
+    }
+
+    if let Some(item) = &rule.onepassworditem {
+        println!(
+            "{} {}",
+            format!("{}🔐 1Password item:", indent).cyan(),
+            item.green()
+        );
+    }
+}
+
+fn print_key_list(indent: &str, label: &str, keys: &[String]) {
+    if keys.is_empty() {
+        return;
+    }
+    println!("{}", format!("{}🔑 {}:", indent, label).cyan());
+    for key in keys {
+        println!("{}  - {}", indent, key.green());
+    }
+}
@@ -0,0 +1,176 @@
+use crate::util::op_key::get_age_identity_for_recipients;
+use crate::util::sops_config::{find_creation_rule, read_or_create_config};
+use crate::util::sops_document::{SopsDocument, SopsFormat, SopsMetadata};
+use crate::util::tree_merge::merge_trees;
+use colored::Colorize;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Entry point for the `merge-driver` command, called by git as
+/// `opsops merge-driver %O %A %B %P`. The three versions are decrypted, merged key by key and
+/// the result is re-encrypted into `%A`. Conflicting keys get conflict markers inside their
+/// (encrypted) value and make the command exit with 1, like git does for text conflicts.
+pub fn merge_driver(base: OsString, ours: OsString, theirs: OsString, path: Option<OsString>) {
+    let (base, ours, theirs) = (
+        PathBuf::from(base),
+        PathBuf::from(ours),
+        PathBuf::from(theirs),
+    );
+    let path = path.map(PathBuf::from);
+
+    // git's temporary files have no extension, the real path tells the format
+    let format = match &path {
+        Some(path) => SopsFormat::from_path(path),
+        None => fs::read_to_string(&ours).ok().map(|c| guess_format(&c)),
+    };
+    let Some(format) = format else {
+        text_merge(&base, &ours, &theirs);
+        return;
+    };
+
+    let load = |p: &Path| {
+        fs::read_to_string(p)
+            .map_err(|e| format!("Failed to read {}: {}", p.display(), e))
+            .and_then(|c| SopsDocument::parse(&c, format))
+    };
+
+    // Files that aren't (or no longer are) sops documents are merged as text
+    let (ours_document, theirs_document) = match (load(&ours), load(&theirs)) {
+        (Ok(o), Ok(t)) => (o, t),
+        _ => {
+            text_merge(&base, &ours, &theirs);
+            return;
+        }
+    };
+    // An empty base means both sides added the file
+    let base_document = fs::read_to_string(&base)
+        .ok()
+        .filter(|c| !c.trim().is_empty())
+        .and_then(|c| SopsDocument::parse(&c, format).ok());
+
+    for document in [&ours_document, &theirs_document] {
+        if let Some(reason) = document.requires_sops() {
+            eprintln!("{} {}", "❌ Can't merge natively:".red(), reason);
+            std::process::exit(1);
+        }
+    }
+
+    // The key has to match one of the recipients of our version
+    let recipients = ours_document.metadata.recipients();
+    let identity =
+        match get_age_identity_for_recipients(path.as_deref().unwrap_or(&ours), &recipients) {
+            Ok(i) => i,
+            Err(e) => {
+                eprintln!("{} {}", "❌ Failed to get Age key:".red(), e);
+                std::process::exit(1);
+            }
+        };
+
+    let (ours_tree, theirs_tree) = match (
+        ours_document.decrypt(&identity),
+        theirs_document.decrypt(&identity),
+    ) {
+        (Ok(o), Ok(t)) => (o, t),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{} {}", "❌ Error while decrypting the file:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    // Without a readable ancestor every difference becomes a conflict
+    let base_tree = base_document.and_then(|d| match d.decrypt(&identity) {
+        Ok(tree) => Some(tree),
+        Err(e) => {
+            eprintln!(
+                "{} {}",
+                "⚠️  Merging without the common ancestor:".yellow(),
+                e
+            );
+            None
+        }
+    });
+
+    let merged = merge_trees(base_tree.as_ref(), &ours_tree, &theirs_tree);
+
+    // Recipients come from .sops.yaml, falling back to the ones our version was encrypted for
+    let config = read_or_create_config().unwrap_or_default();
+    let rule = path
+        .as_ref()
+        .and_then(|path| find_creation_rule(&config, path).ok().flatten());
+    let reason = match rule {
+        Some((_, rule)) => rule.requires_sops(),
+        None => ours_document.requires_sops_to_rewrite(),
+    }
+    .or_else(|| config.store_requires_sops(format));
+    if let Some(reason) = reason {
+        eprintln!("{} {}", "❌ Can't merge natively:".red(), reason);
+        std::process::exit(1);
+    }
+    let (recipients, metadata) = match rule {
+        Some((_, rule)) => (config.rule_recipients(rule), SopsMetadata::for_rule(rule)),
+        None => {
+            let mut metadata = ours_document.metadata.clone();
+            metadata.key_groups.clear();
+            (ours_document.metadata.recipients(), metadata)
+        }
+    };
+
+    let encrypted = SopsDocument::encrypt(merged.tree, format, &recipients, metadata)
+        .and_then(|document| document.emit(config.store_indent(format)));
+    let written = encrypted.and_then(|contents| {
+        fs::write(&ours, contents).map_err(|e| format!("Failed to write merge result: {}", e))
+    });
+    if let Err(e) = written {
+        eprintln!(
+            "{} {}",
+            "❌ Error while encrypting the merge result:".red(),
+            e
+        );
+        std::process::exit(1);
+    }
+
+    if !merged.conflicts.is_empty() {
+        let name = path.as_deref().unwrap_or(&ours).display().to_string();
+        eprintln!(
+            "{} {} {}",
+            "❌ Conflicting changes in".red(),
+            name,
+            "(resolve them with `opsops edit`):".red()
+        );
+        for key in &merged.conflicts {
+            eprintln!("   {}", key);
+        }
+        std::process::exit(1);
+    }
+}
+
+/// sops writes JSON files as a single object, everything else is YAML
+fn guess_format(contents: &str) -> SopsFormat {
+    if contents.trim_start().starts_with('{') {
+        SopsFormat::Json
+    } else {
+        SopsFormat::Yaml
+    }
+}
+
+/// Fall back to git's line based merge, which leaves conflict markers in `ours`
+fn text_merge(base: &Path, ours: &Path, theirs: &Path) {
+    let status = Command::new("git")
+        .arg("merge-file")
+        .args(["-L", "ours", "-L", "base", "-L", "theirs"])
+        .arg(ours)
+        .arg(base)
+        .arg(theirs)
+        .status();
+
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(_) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to run git merge-file:".red(), e);
+            std::process::exit(1);
+        }
+    }
+}
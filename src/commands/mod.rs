@@ -1,7 +1,22 @@
+pub mod age_plugin;
+pub mod agent;
+pub mod cat;
+pub mod config_validate;
 pub mod decrypt;
 pub mod doctor;
 pub mod edit;
 pub mod encrypt;
+pub mod exec;
+pub mod exec_file;
 pub mod generate_age_key;
+pub mod git_setup;
+pub mod hook;
 pub mod init;
+pub mod keyservice;
 pub mod list_config;
+pub mod merge_driver;
+pub mod recipients;
+pub mod rotate;
+pub mod sops_editor;
+pub mod status;
+pub mod which_rule;
@@ -0,0 +1,421 @@
+use crate::util::bulk::{BulkResult, BulkStatus, print_summary};
+use crate::util::op_key::{get_age_identity_for_recipients, get_age_key_from_1password};
+use crate::util::output_path::replace_file;
+use crate::util::project_files::ProjectFiles;
+use crate::util::sops_command::SopsCommandBuilder;
+use crate::util::sops_config::{find_creation_rule, read_or_create_config, write_config};
+use crate::util::sops_document::{SopsDocument, SopsFormat, is_encrypted_file};
+use crate::util::sops_structs::{CreationRule, SopsConfig, TeamMember};
+use age::x25519::{Identity, Recipient};
+use colored::Colorize;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Entry point for `recipients add`: adds a public key to the selected creation rules (all of
+/// them by default) and wraps the data key of every file under those rules for it, so the new
+/// recipient can decrypt them right away
+pub fn recipients_add(key: String, rule: Option<String>, name: Option<String>) {
+    let mut config = load_config();
+
+    if let Err(e) = Recipient::from_str(&key) {
+        eprintln!("{} {}", "❌ Invalid age public key:".red(), e);
+        std::process::exit(1);
+    }
+    if let Some(name) = &name
+        && let Some(existing) = config.recipients.get(name)
+        && existing.key() != key
+    {
+        eprintln!(
+            "{} {} {}",
+            "❌ The name".red(),
+            name,
+            format!("already belongs to {}, remove it first", existing.key()).red()
+        );
+        std::process::exit(1);
+    }
+
+    // With a name the rules list the name, which reads better than the key
+    let entry = name.clone().unwrap_or_else(|| key.clone());
+    if let Some(name) = name {
+        config
+            .recipients
+            .entry(name)
+            .or_insert_with(|| TeamMember::Key(key.clone()));
+    }
+
+    let selected = select_rules(&config, rule.as_deref()).unwrap_or_else(|e| {
+        eprintln!("{} {}", "❌".red(), e);
+        std::process::exit(1);
+    });
+    let mut changed = Vec::new();
+    for i in selected {
+        if config
+            .rule_recipients(&config.creation_rules[i])
+            .contains(&key)
+        {
+            continue;
+        }
+        match config.creation_rules[i].add_age_recipient(&entry) {
+            Ok(true) => changed.push(i),
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!(
+                    "{} {}",
+                    format!("❌ Can't add to rule #{}:", i + 1).red(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if changed.is_empty() {
+        save_config(&config);
+        println!(
+            "{} {}",
+            "ℹ️ Already a recipient of the selected rules:".blue(),
+            entry
+        );
+        return;
+    }
+
+    save_config(&config);
+    println!(
+        "{} {} {}",
+        "✅ Added".green(),
+        entry,
+        format!("to {}", describe_rules(&changed)).green()
+    );
+
+    update_files(&config, &changed, false);
+}
+
+/// Entry point for `recipients remove`: removes a public key (or the name it was added with)
+/// from the selected creation rules and re-encrypts their files under a fresh data key, so
+/// the removed recipient can't decrypt anything that changes from now on
+pub fn recipients_remove(recipient: String, rule: Option<String>) {
+    let mut config = load_config();
+    let key = config.resolve_recipient(&recipient);
+    let label = config.recipient_label(&recipient);
+
+    // Rules may list the key itself or any name it is known by
+    let mut entries = config.recipient_names(&key);
+    entries.push(key.clone());
+
+    let selected = select_rules(&config, rule.as_deref()).unwrap_or_else(|e| {
+        eprintln!("{} {}", "❌".red(), e);
+        std::process::exit(1);
+    });
+    let mut changed = Vec::new();
+    for i in selected {
+        let mut removed = false;
+        for entry in &entries {
+            removed |= config.creation_rules[i].remove_age_recipient(entry);
+        }
+        if !removed {
+            continue;
+        }
+        if config.rule_recipients(&config.creation_rules[i]).is_empty() {
+            eprintln!(
+                "{} rule #{} would have no recipients left",
+                format!("❌ Can't remove {}:", label).red(),
+                i + 1
+            );
+            std::process::exit(1);
+        }
+        changed.push(i);
+    }
+
+    if changed.is_empty() {
+        println!(
+            "{} {}",
+            "ℹ️ Not a recipient of the selected rules:".blue(),
+            label
+        );
+        return;
+    }
+
+    // Forget the name once no rule uses the key anymore
+    if !config
+        .creation_rules
+        .iter()
+        .any(|rule| config.rule_recipients(rule).contains(&key))
+    {
+        config.recipients.retain(|_, member| member.key() != key);
+    }
+
+    save_config(&config);
+    println!(
+        "{} {} {}",
+        "✅ Removed".green(),
+        label,
+        format!("from {}", describe_rules(&changed)).green()
+    );
+
+    update_files(&config, &changed, true);
+}
+
+fn load_config() -> SopsConfig {
+    match read_or_create_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to read SOPS config:".red(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn save_config(config: &SopsConfig) {
+    if let Err(e) = write_config(config) {
+        eprintln!("{} {}", "❌ Failed to write SOPS config:".red(), e);
+        std::process::exit(1);
+    }
+}
+
+/// Indices of the rules `--rule` selects: a rule number as shown by `list-config`, or the
+/// exact `path_regex` of one or more rules. Without `--rule` every rule is selected.
+fn select_rules(config: &SopsConfig, selector: Option<&str>) -> Result<Vec<usize>, String> {
+    let Some(selector) = selector else {
+        return Ok((0..config.creation_rules.len()).collect());
+    };
+
+    let selected: Vec<usize> = match selector.parse::<usize>() {
+        Ok(n) if (1..=config.creation_rules.len()).contains(&n) => vec![n - 1],
+        Ok(_) => Vec::new(),
+        Err(_) => config
+            .creation_rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.path_regex.as_deref() == Some(selector))
+            .map(|(i, _)| i)
+            .collect(),
+    };
+
+    if selected.is_empty() {
+        return Err(format!("No creation rule matches --rule {}", selector));
+    }
+    Ok(selected)
+}
+
+fn describe_rules(rules: &[usize]) -> String {
+    let numbers: Vec<String> = rules.iter().map(|i| format!("#{}", i + 1)).collect();
+    format!("rule {}", numbers.join(", "))
+}
+
+/// Bring every encrypted file under the changed rules in line with their new recipients
+fn update_files(config: &SopsConfig, rules: &[usize], rotate_data_key: bool) {
+    let project = match ProjectFiles::discover() {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{} {}", "❌".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    // Keys already fetched from 1Password, most files can be opened with the same one
+    let mut identities = Vec::new();
+    let mut results = Vec::new();
+    for path in project.files() {
+        if !is_encrypted_file(&path) {
+            continue;
+        }
+        let Ok(Some((i, rule))) = find_creation_rule(config, &path) else {
+            continue;
+        };
+        if !rules.contains(&i) {
+            continue;
+        }
+
+        let status = match update_file(config, &path, rule, rotate_data_key, &mut identities) {
+            Ok(()) => BulkStatus::Changed(String::new()),
+            Err(e) => BulkStatus::Failed(e),
+        };
+        results.push(BulkResult {
+            file: project.display_path(&path),
+            status,
+        });
+    }
+
+    if print_summary("updated", &results) {
+        std::process::exit(1);
+    }
+}
+
+fn update_file(
+    config: &SopsConfig,
+    path: &Path,
+    rule: &CreationRule,
+    rotate_data_key: bool,
+    identities: &mut Vec<Identity>,
+) -> Result<(), String> {
+    let document = SopsFormat::from_path(path)
+        .and_then(|_| SopsDocument::load(path).ok())
+        .filter(|d| {
+            d.requires_sops_to_rewrite().is_none()
+                && rule.requires_sops().is_none()
+                && config.store_requires_sops(d.format).is_none()
+        });
+    let Some(mut document) = document else {
+        return update_with_sops(path, rotate_data_key);
+    };
+
+    let current = document.metadata.recipients();
+    let identity = match identities
+        .iter()
+        .find(|i| current.contains(&i.to_public().to_string()))
+    {
+        Some(identity) => identity.clone(),
+        None => {
+            let identity = get_age_identity_for_recipients(path, &current)?;
+            identities.push(identity.clone());
+            identity
+        }
+    };
+
+    let recipients = config.rule_recipients(rule);
+    let indent = config.store_indent(document.format);
+    let contents = if rotate_data_key {
+        document.reencrypt(&identity, &recipients)?.emit(indent)?
+    } else {
+        document.rewrap(&identity, &recipients)?;
+        document.emit(indent)?
+    };
+    replace_file(path, contents.as_bytes())
+}
+
+/// `sops updatekeys`, followed by `sops --rotate` for a fresh data key
+fn update_with_sops(path: &Path, rotate_data_key: bool) -> Result<(), String> {
+    if which::which("sops").is_err() {
+        return Err("'sops' is not installed or not in PATH. Please install it first.".to_string());
+    }
+
+    let age_key =
+        get_age_key_from_1password(path).map_err(|e| format!("Failed to get Age key: {}", e))?;
+    let mut commands = vec![vec!["updatekeys", "--yes"]];
+    if rotate_data_key {
+        commands.push(vec!["--rotate", "--in-place"]);
+    }
+
+    for args in commands {
+        let status = SopsCommandBuilder::new()
+            .args(args)
+            .arg(path)
+            .with_key(&age_key)
+            .status()
+            .map_err(|e| format!("Failed to launch sops: {}", e))?;
+        if !status.success() {
+            return Err(format!("sops exited with {}", status));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::sops_document::SopsMetadata;
+
+    fn config(recipients: &[&Identity]) -> SopsConfig {
+        let keys: Vec<String> = recipients
+            .iter()
+            .map(|i| i.to_public().to_string())
+            .collect();
+        serde_yaml::from_str(&format!(
+            "creation_rules:\n  - path_regex: \\.enc\\.yaml$\n    age: {}\n  - path_regex: \\.enc\\.json$\n    age: {}\n  - path_regex: \\.enc\\.json$\n    age: {}\n",
+            keys.join(","),
+            keys[0],
+            keys[0]
+        ))
+        .unwrap()
+    }
+
+    /// Writes a file encrypted under the first rule of `config`
+    fn encrypted_file(dir: &Path, config: &SopsConfig) -> std::path::PathBuf {
+        let rule = &config.creation_rules[0];
+        let tree = serde_yaml::from_str("password: hunter2\n").unwrap();
+        let document = SopsDocument::encrypt(
+            tree,
+            SopsFormat::Yaml,
+            &config.rule_recipients(rule),
+            SopsMetadata::for_rule(rule),
+        )
+        .unwrap();
+        let path = dir.join("secrets.enc.yaml");
+        std::fs::write(&path, document.emit(None).unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn added_recipients_can_decrypt_with_the_same_data_key() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let path = encrypted_file(dir.path(), &config(&[&alice]));
+        let before = SopsDocument::load(&path).unwrap().metadata;
+
+        let config = config(&[&alice, &bob]);
+        let mut identities = vec![alice.clone()];
+        update_file(
+            &config,
+            &path,
+            &config.creation_rules[0],
+            false,
+            &mut identities,
+        )
+        .unwrap();
+
+        let document = SopsDocument::load(&path).unwrap();
+        assert_eq!(
+            *document.metadata.data_key(&bob).unwrap(),
+            *before.data_key(&alice).unwrap()
+        );
+        assert_eq!(document.decrypt(&bob).unwrap()["password"], "hunter2");
+    }
+
+    #[test]
+    fn removed_recipients_lose_access_to_a_fresh_data_key() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let dir = tempfile::tempdir().unwrap();
+        let path = encrypted_file(dir.path(), &config(&[&alice, &bob]));
+        let before = SopsDocument::load(&path).unwrap().metadata;
+
+        let config = config(&[&alice]);
+        let mut identities = vec![alice.clone()];
+        update_file(
+            &config,
+            &path,
+            &config.creation_rules[0],
+            true,
+            &mut identities,
+        )
+        .unwrap();
+
+        let document = SopsDocument::load(&path).unwrap();
+        assert!(document.metadata.data_key(&bob).is_err());
+        assert_ne!(
+            *document.metadata.data_key(&alice).unwrap(),
+            *before.data_key(&bob).unwrap()
+        );
+        assert_eq!(document.decrypt(&alice).unwrap()["password"], "hunter2");
+    }
+
+    #[test]
+    fn rules_are_selected_by_number_or_path_regex() {
+        let config = config(&[&Identity::generate()]);
+        assert_eq!(select_rules(&config, None).unwrap(), vec![0, 1, 2]);
+        assert_eq!(select_rules(&config, Some("2")).unwrap(), vec![1]);
+        assert_eq!(
+            select_rules(&config, Some("\\.enc\\.json$")).unwrap(),
+            vec![1, 2]
+        );
+
+        for selector in ["0", "4", "\\.json$"] {
+            let error = select_rules(&config, Some(selector)).unwrap_err();
+            assert_eq!(
+                error,
+                format!("No creation rule matches --rule {}", selector)
+            );
+        }
+    }
+}
@@ -0,0 +1,527 @@
+use crate::commands::generate_age_key::save_to_op;
+use crate::util::bulk::{BulkResult, BulkStatus, print_summary};
+use crate::util::op::split_reference;
+use crate::util::op_key::{age_identity, public_key_for_reference, read_age_key};
+use crate::util::output_path::{replace_file, write_private_file};
+use crate::util::project_files::ProjectFiles;
+use crate::util::sops_command::SopsCommandBuilder;
+use crate::util::sops_config::{read_or_create_config, write_config};
+use crate::util::sops_document::{SopsDocument, SopsFormat, file_recipients};
+use crate::util::sops_structs::{SopsConfig, TeamMember};
+use age::secrecy::SecretString;
+use age::x25519::Identity;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Progress of an unfinished rotation, kept next to the project so it can be resumed
+const STATE_FILE: &str = "opsops-rotate";
+
+/// Field the new private key is stored in, matching `generate-age-key`
+const PRIVATE_KEY_FIELD: &str = "Private Key";
+
+/// Everything needed to finish a rotation after it was interrupted. The new private key only
+/// lives in 1Password, the files are re-encrypted with its public key.
+#[derive(Serialize, Deserialize)]
+struct RotationState {
+    old_reference: String,
+    old_public_key: String,
+    new_reference: String,
+    new_public_key: Option<String>,
+    /// Set before the new item is created, so a rotation interrupted right after creating it
+    /// picks the item up again instead of creating a second one
+    #[serde(default)]
+    creating_item: bool,
+    rules_updated: bool,
+    /// Files still encrypted for the old key, relative to the project root
+    pending: Vec<String>,
+}
+
+/// Entry point for the `rotate` command: replaces an age key with a new one stored in
+/// 1Password. The steps run in order and each is recorded in a state file, so an interrupted
+/// rotation continues where it stopped the next time `rotate` runs:
+///
+/// 1. generate the new key and store it in 1Password
+/// 2. replace the old public key in every creation rule
+/// 3. re-encrypt every file the old key can decrypt, with a fresh data key
+/// 4. point the 1Password references at the new key
+pub fn rotate(item: Option<String>, title: Option<String>, dry_run: bool) {
+    let mut config = match read_or_create_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to read SOPS config:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let project = match ProjectFiles::discover() {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{} {}", "❌".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let state_path = project.state_path(STATE_FILE);
+    let state = if state_path.exists() {
+        if item.is_some() || title.is_some() {
+            println!(
+                "{}",
+                "⚠️  Ignoring --item and --title while resuming a rotation".yellow()
+            );
+        }
+        load_state(&state_path).inspect(|_| {
+            println!(
+                "{} {}",
+                "ℹ️ Resuming the rotation recorded in".blue(),
+                state_path.display()
+            )
+        })
+    } else {
+        plan(&config, &project, item, title)
+    };
+    let mut state = match state {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to plan the key rotation:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    print_plan(&config, &state);
+    if dry_run {
+        println!("{}", "ℹ️ Dry run, nothing was changed.".blue());
+        return;
+    }
+
+    if let Err(e) = run(&mut config, &project, &state_path, &mut state) {
+        eprintln!("{} {}", "❌ Key rotation failed:".red(), e);
+        if state_path.exists() {
+            eprintln!(
+                "{}",
+                "Run `opsops rotate` again to continue where it stopped.".dimmed()
+            );
+        }
+        std::process::exit(1);
+    }
+}
+
+/// Work out what a new rotation of the key behind `item` (a name from `onepassworditems` or
+/// an `op://` reference, by default the project wide key) has to change
+fn plan(
+    config: &SopsConfig,
+    project: &ProjectFiles,
+    item: Option<String>,
+    title: Option<String>,
+) -> Result<RotationState, String> {
+    let old_reference = match item {
+        Some(item) => config.onepassworditems.get(&item).cloned().unwrap_or(item),
+        None => config.onepassword_reference(None).ok_or_else(|| {
+            "No onepassworditem in .sops.yaml, use --item to choose the key to rotate".to_string()
+        })?,
+    };
+    let (vault, old_title, _) = split_reference(&old_reference)?;
+    let old_public_key = public_key_for_reference(&old_reference)?;
+
+    let today = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+    let title = title.unwrap_or_else(|| format!("{} (rotated {})", old_title, &today[..10]));
+    let new_reference = format!("op://{}/{}/{}", vault, title, PRIVATE_KEY_FIELD);
+
+    let pending = project
+        .files()
+        .into_iter()
+        .filter(|path| file_recipients(path).is_ok_and(|r| r.contains(&old_public_key)))
+        .map(|path| project.display_path(&path))
+        .collect();
+
+    Ok(RotationState {
+        old_reference,
+        old_public_key,
+        new_reference,
+        new_public_key: None,
+        creating_item: false,
+        rules_updated: false,
+        pending,
+    })
+}
+
+fn print_plan(config: &SopsConfig, state: &RotationState) {
+    let done = |done: bool| {
+        if done {
+            " (done)".green().to_string()
+        } else {
+            String::new()
+        }
+    };
+
+    println!(
+        "{} {} {}",
+        "🔄 Rotating the age key".green(),
+        state.old_public_key,
+        format!("from {}", state.old_reference).dimmed()
+    );
+
+    println!(
+        "   1. Store a new age key at {}{}",
+        state.new_reference,
+        done(state.new_public_key.is_some())
+    );
+
+    let current_key = match &state.new_public_key {
+        Some(new) if state.rules_updated => new,
+        _ => &state.old_public_key,
+    };
+    let rules: Vec<String> = config
+        .creation_rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| config.rule_recipients(rule).contains(current_key))
+        .map(|(i, _)| format!("#{}", i + 1))
+        .collect();
+    println!(
+        "   2. Replace the public key in {}{}",
+        if rules.is_empty() {
+            "no creation rule".to_string()
+        } else {
+            format!("creation rules {}", rules.join(", "))
+        },
+        done(state.rules_updated)
+    );
+
+    println!(
+        "   3. Re-encrypt {} files{}",
+        state.pending.len(),
+        done(state.rules_updated && state.pending.is_empty())
+    );
+    for file in &state.pending {
+        println!("        {}", file);
+    }
+
+    let uses = reference_uses(config, &state.old_reference);
+    println!(
+        "   4. Point {} to the new key",
+        if uses.is_empty() {
+            "no 1Password reference".to_string()
+        } else {
+            uses.join(", ")
+        }
+    );
+}
+
+/// Carry out the remaining steps, recording each one before moving on
+fn run(
+    config: &mut SopsConfig,
+    project: &ProjectFiles,
+    state_path: &Path,
+    state: &mut RotationState,
+) -> Result<(), String> {
+    let new_public_key = match &state.new_public_key {
+        Some(key) => key.clone(),
+        None => {
+            let existing = if state.creating_item {
+                public_key_for_reference(&state.new_reference).ok()
+            } else {
+                None
+            };
+            let public_key = match existing {
+                Some(public_key) => public_key,
+                None => {
+                    state.creating_item = true;
+                    save_state(state_path, state)?;
+
+                    let (vault, title, _) = split_reference(&state.new_reference)?;
+                    let key = Identity::generate();
+                    save_to_op(&key, vault, title)?;
+                    key.to_public().to_string()
+                }
+            };
+
+            state.new_public_key = Some(public_key.clone());
+            save_state(state_path, state)?;
+            println!(
+                "{} {} ({})",
+                "✅ Stored the new age key at".green(),
+                state.new_reference,
+                public_key
+            );
+            public_key
+        }
+    };
+
+    if !state.rules_updated {
+        replace_recipient(config, &state.old_public_key, &new_public_key);
+        write_config(config)?;
+        state.rules_updated = true;
+        save_state(state_path, state)?;
+        println!("{}", "✅ Replaced the old public key in .sops.yaml".green());
+    }
+
+    if !state.pending.is_empty() {
+        let old_key = read_age_key(&state.old_reference)?;
+        let identity = age_identity(&old_key)?;
+
+        let mut results = Vec::new();
+        for file in state.pending.clone() {
+            let path = project.root().join(&file);
+
+            // Files that no longer use the old key were handled before an interruption
+            let status = if file_recipients(&path).is_ok_and(|r| !r.contains(&state.old_public_key))
+            {
+                BulkStatus::UpToDate
+            } else {
+                match rotate_file(
+                    config,
+                    &path,
+                    &old_key,
+                    &identity,
+                    &state.old_public_key,
+                    &new_public_key,
+                ) {
+                    Ok(()) => BulkStatus::Changed(String::new()),
+                    Err(e) => BulkStatus::Failed(e),
+                }
+            };
+
+            if !matches!(status, BulkStatus::Failed(_)) {
+                state.pending.retain(|p| *p != file);
+                save_state(state_path, state)?;
+            }
+            results.push(BulkResult { file, status });
+        }
+
+        if print_summary("rotated", &results) {
+            return Err("some files could not be re-encrypted".to_string());
+        }
+    }
+
+    replace_reference(config, &state.old_reference, &state.new_reference);
+    write_config(config)?;
+    fs::remove_file(state_path)
+        .map_err(|e| format!("Failed to remove {}: {}", state_path.display(), e))?;
+
+    println!(
+        "{} {}",
+        "✅ Rotated the age key, .sops.yaml now uses".green(),
+        state.new_reference
+    );
+    println!(
+        "{}",
+        format!(
+            "Once everybody can decrypt with the new key, delete {} from 1Password.",
+            state.old_reference
+        )
+        .dimmed()
+    );
+    Ok(())
+}
+
+/// Re-encrypt a file with a new data key for its recipients, the old key swapped for the new one
+fn rotate_file(
+    config: &SopsConfig,
+    path: &Path,
+    old_key: &SecretString,
+    identity: &Identity,
+    old_public_key: &str,
+    new_public_key: &str,
+) -> Result<(), String> {
+    let document = SopsFormat::from_path(path)
+        .and_then(|_| SopsDocument::load(path).ok())
+        .filter(|d| {
+            d.requires_sops_to_rewrite().is_none() && config.store_requires_sops(d.format).is_none()
+        });
+    let Some(document) = document else {
+        return rotate_with_sops(path, old_key, old_public_key, new_public_key);
+    };
+
+    let mut recipients: Vec<String> = Vec::new();
+    for recipient in document.metadata.recipients() {
+        let recipient = if recipient == old_public_key {
+            new_public_key.to_string()
+        } else {
+            recipient
+        };
+        if !recipients.contains(&recipient) {
+            recipients.push(recipient);
+        }
+    }
+
+    let contents = document
+        .reencrypt(identity, &recipients)
+        .and_then(|d| d.emit(config.store_indent(d.format)))?;
+    replace_file(path, contents.as_bytes())
+}
+
+/// `sops --rotate` for formats opsops can't rewrite itself
+fn rotate_with_sops(
+    path: &Path,
+    old_key: &SecretString,
+    old_public_key: &str,
+    new_public_key: &str,
+) -> Result<(), String> {
+    if which::which("sops").is_err() {
+        return Err("'sops' is not installed or not in PATH. Please install it first.".to_string());
+    }
+
+    let status = SopsCommandBuilder::new()
+        .args(["--rotate", "--in-place", "--add-age", new_public_key])
+        .args(["--rm-age", old_public_key])
+        .arg(path)
+        .with_key(old_key)
+        .status()
+        .map_err(|e| format!("Failed to launch sops: {}", e))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("sops exited with {}", status))
+    }
+}
+
+/// Swap a public key in the `age` field and key groups of every creation rule (and recreation
+/// rule of `destination_rules`), and in the team directory for rules that list it by name
+fn replace_recipient(config: &mut SopsConfig, old: &str, new: &str) {
+    for member in config.recipients.values_mut() {
+        match member {
+            TeamMember::Key(key) | TeamMember::Member { key, .. } if key == old => {
+                *key = new.to_string();
+            }
+            _ => {}
+        }
+    }
+
+    let rules = config.creation_rules.iter_mut().chain(
+        config
+            .destination_rules
+            .iter_mut()
+            .filter_map(|d| d.recreation_rule.as_mut()),
+    );
+    for rule in rules {
+        if rule.age_recipients().iter().any(|key| key == old) {
+            rule.map_age_recipients(|key| {
+                if key == old {
+                    new.to_string()
+                } else {
+                    key.to_string()
+                }
+            });
+        }
+    }
+}
+
+/// Where the config refers to a 1Password reference, for display
+fn reference_uses(config: &SopsConfig, reference: &str) -> Vec<String> {
+    let mut uses = Vec::new();
+    if config.onepassworditem == reference {
+        uses.push("onepassworditem".to_string());
+    }
+    for (name, value) in &config.onepassworditems {
+        if value == reference {
+            uses.push(format!("onepassworditems.{}", name));
+        }
+    }
+    for (i, rule) in config.creation_rules.iter().enumerate() {
+        if rule.onepassworditem.as_deref() == Some(reference) {
+            uses.push(format!("rule #{}", i + 1));
+        }
+    }
+    for (name, member) in &config.recipients {
+        if let TeamMember::Member {
+            onepassworditem: Some(value),
+            ..
+        } = member
+            && value == reference
+        {
+            uses.push(format!("recipients.{}", name));
+        }
+    }
+    uses
+}
+
+/// Point every use of a 1Password reference at another one
+fn replace_reference(config: &mut SopsConfig, old: &str, new: &str) {
+    let references = std::iter::once(&mut config.onepassworditem)
+        .chain(config.onepassworditems.values_mut())
+        .chain(
+            config
+                .creation_rules
+                .iter_mut()
+                .filter_map(|rule| rule.onepassworditem.as_mut()),
+        )
+        .chain(
+            config
+                .recipients
+                .values_mut()
+                .filter_map(|member| match member {
+                    TeamMember::Member {
+                        onepassworditem, ..
+                    } => onepassworditem.as_mut(),
+                    TeamMember::Key(_) => None,
+                }),
+        );
+
+    for reference in references {
+        if reference == old {
+            *reference = new.to_string();
+        }
+    }
+}
+
+fn load_state(path: &Path) -> Result<RotationState, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("Invalid {}: {}", path.display(), e))
+}
+
+fn save_state(path: &Path, state: &RotationState) -> Result<(), String> {
+    let contents = serde_json::to_string_pretty(state)
+        .map_err(|e| format!("Failed to serialize rotation state: {}", e))?;
+    if path.exists() {
+        replace_file(path, contents.as_bytes())
+    } else {
+        write_private_file(path, contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replace_reference_updates_every_use() {
+        let mut config: SopsConfig = serde_yaml::from_str(
+            r#"
+creation_rules:
+  - path_regex: ^prod/
+    age: alice
+    onepassworditem: op://V/old/Private Key
+onepassworditem: op://V/old/Private Key
+onepassworditems:
+  prod: op://V/old/Private Key
+  dev: op://V/dev/Private Key
+recipients:
+  alice:
+    key: age1alice
+    onepassworditem: op://V/old/Private Key
+  bob: age1bob
+"#,
+        )
+        .unwrap();
+        let old = "op://V/old/Private Key";
+        assert_eq!(
+            reference_uses(&config, old),
+            [
+                "onepassworditem",
+                "onepassworditems.prod",
+                "rule #1",
+                "recipients.alice"
+            ]
+        );
+
+        replace_reference(&mut config, old, "op://V/new/Private Key");
+        assert!(reference_uses(&config, old).is_empty());
+        assert_eq!(reference_uses(&config, "op://V/new/Private Key").len(), 4);
+        assert_eq!(config.onepassworditems["dev"], "op://V/dev/Private Key");
+    }
+}
@@ -0,0 +1,68 @@
+use crate::util::sops_command::EDITOR_ENV;
+use colored::Colorize;
+use std::ffi::OsString;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// Editors sops falls back to when `EDITOR` is unset, in the order it tries them
+const FALLBACK_EDITORS: [&str; 3] = ["vim", "nano", "vi"];
+
+/// Entry point for `sops-editor`: sops runs it as `EDITOR` during `opsops edit`. It starts the
+/// user's editor on the decrypted file without the Age key in its environment or descriptors.
+pub fn sops_editor(args: Vec<OsString>) {
+    let editor = std::env::var_os(EDITOR_ENV).filter(|e| !e.is_empty());
+    close_inherited_descriptors();
+
+    let mut command = match editor {
+        Some(editor) => {
+            // EDITOR may hold arguments, like `code --wait`
+            let mut script = editor.clone();
+            script.push(" \"$@\"");
+            let mut command = Command::new("sh");
+            command.env("EDITOR", &editor);
+            command.arg("-c").arg(script).arg(editor);
+            command
+        }
+        None => match FALLBACK_EDITORS.iter().find(|e| which::which(e).is_ok()) {
+            Some(editor) => {
+                let mut command = Command::new(editor);
+                command.env_remove("EDITOR");
+                command
+            }
+            None => {
+                eprintln!(
+                    "{}",
+                    "❌ No editor found, set EDITOR to the editor you want to use.".red()
+                );
+                std::process::exit(1);
+            }
+        },
+    };
+
+    // The environment is changed on the command only, so no other thread sees it change
+    let e = command
+        .env_remove("SOPS_AGE_KEY")
+        .env_remove("SOPS_AGE_KEY_FILE")
+        .env_remove(EDITOR_ENV)
+        .args(args)
+        .exec();
+    eprintln!("{} {}", "❌ Failed to start the editor:".red(), e);
+    std::process::exit(1);
+}
+
+/// Mark every descriptor but stdin, stdout and stderr close-on-exec, sops passes on the one
+/// it read the key from
+fn close_inherited_descriptors() {
+    let Ok(entries) = std::fs::read_dir("/dev/fd") else {
+        return;
+    };
+    let fds: Vec<i32> = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|fd| *fd > 2)
+        .collect();
+    for fd in fds {
+        // SAFETY: F_SETFD only changes the flags of the descriptor and takes no pointers; a
+        // descriptor that closed since /dev/fd was listed makes it fail with EBADF
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+}
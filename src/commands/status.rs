@@ -0,0 +1,213 @@
+use crate::util::bulk::is_up_to_date;
+use crate::util::output_path::{encrypted_path, has_encrypted_marker};
+use crate::util::project_files::{ProjectFiles, SecretFile};
+use crate::util::sops_config::{find_secret_rule, read_or_create_config};
+use crate::util::sops_document::file_recipients;
+use crate::util::sops_structs::SopsConfig;
+use colored::{ColoredString, Colorize};
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// The state of a file that matches a creation rule
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum FileState {
+    /// Encrypted for exactly the recipients of its rule
+    Encrypted,
+    /// Plaintext working copy of an encrypted file that exists next to it
+    Decrypted,
+    /// Plaintext without an encrypted copy, it should be encrypted
+    Plaintext,
+    /// Encrypted, but for other recipients than its rule lists
+    Drifted,
+}
+
+#[derive(Serialize)]
+struct FileStatus {
+    file: String,
+    state: FileState,
+    /// Number of the creation rule the file matches, counting from 1 like `list-config`
+    rule: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    missing_recipients: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    extra_recipients: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<String>,
+}
+
+/// Entry point for the `status` command: reports for every file matching a creation rule
+/// whether it is encrypted, plaintext or encrypted for the wrong recipients.
+pub fn status(format: String) {
+    let config = match read_or_create_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to read SOPS config:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let project = match ProjectFiles::discover() {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{} {}", "❌".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let statuses: Vec<FileStatus> = project
+        .secret_files(&config)
+        .into_iter()
+        .filter_map(|file| file_status(&config, &project, file))
+        .collect();
+
+    if format == "json" {
+        match serde_json::to_string_pretty(&statuses) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("{} {}", "❌ Failed to serialize status:".red(), e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        print_table(&config, &statuses);
+    }
+}
+
+fn file_status(
+    config: &SopsConfig,
+    project: &ProjectFiles,
+    file: SecretFile,
+) -> Option<FileStatus> {
+    let (rule_index, rule) = find_secret_rule(config, &file.path)?;
+    let markers = config.encrypted_markers();
+
+    let mut status = FileStatus {
+        file: project.display_path(&file.path),
+        state: FileState::Encrypted,
+        rule: rule_index + 1,
+        missing_recipients: Vec::new(),
+        extra_recipients: Vec::new(),
+        details: None,
+    };
+
+    if !file.encrypted {
+        // A plaintext file is only a working copy if its encrypted counterpart exists
+        let encrypted = (!has_encrypted_marker(&file.path, &markers))
+            .then(|| encrypted_path(&file.path, &markers))
+            .flatten()
+            .filter(|e| e.is_file());
+
+        match encrypted {
+            Some(encrypted) => {
+                status.state = FileState::Decrypted;
+                if !is_up_to_date(&file.path, &encrypted) {
+                    status.details =
+                        Some(format!("newer than {}", project.display_path(&encrypted)));
+                }
+            }
+            None => status.state = FileState::Plaintext,
+        }
+        return Some(status);
+    }
+
+    match file_recipients(&file.path) {
+        Ok(recipients) => {
+            let actual: BTreeSet<String> = recipients.into_iter().collect();
+            let expected: BTreeSet<String> = config.rule_recipients(rule).into_iter().collect();
+
+            status.missing_recipients = expected.difference(&actual).cloned().collect();
+            status.extra_recipients = actual.difference(&expected).cloned().collect();
+            if !status.missing_recipients.is_empty() || !status.extra_recipients.is_empty() {
+                status.state = FileState::Drifted;
+            }
+        }
+        Err(e) => status.details = Some(e),
+    }
+
+    Some(status)
+}
+
+/// Recipients are shown by their name from the team directory, JSON output keeps the keys
+fn print_table(config: &SopsConfig, statuses: &[FileStatus]) {
+    if statuses.is_empty() {
+        println!(
+            "{}",
+            "ℹ️ No files matched the creation rules in .sops.yaml".blue()
+        );
+        return;
+    }
+
+    let width = statuses
+        .iter()
+        .map(|s| s.file.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+
+    println!(
+        "{:<width$}  {:<9}  {:<4}  {}",
+        "FILE".bold(),
+        "STATE".bold(),
+        "RULE".bold(),
+        "DETAILS".bold()
+    );
+    for status in statuses {
+        let mut details: Vec<String> = status
+            .missing_recipients
+            .iter()
+            .map(|r| format!("+{}", config.recipient_label(r)))
+            .chain(
+                status
+                    .extra_recipients
+                    .iter()
+                    .map(|r| format!("-{}", config.recipient_label(r))),
+            )
+            .collect();
+        details.extend(status.details.clone());
+
+        println!(
+            "{:<width$}  {}  {:<4}  {}",
+            status.file,
+            state_label(status.state),
+            format!("#{}", status.rule),
+            details.join(" ")
+        );
+    }
+}
+
+fn state_label(state: FileState) -> ColoredString {
+    match state {
+        FileState::Encrypted => format!("{:<9}", "encrypted").green(),
+        FileState::Decrypted => format!("{:<9}", "decrypted").blue(),
+        FileState::Plaintext => format!("{:<9}", "plaintext").red(),
+        FileState::Drifted => format!("{:<9}", "drifted").yellow(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plaintext_under_a_suffix_rule_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("foo.yaml"), "password: hunter2\n").unwrap();
+        let config: SopsConfig = serde_yaml::from_str(
+            "creation_rules:\n  - path_regex: \\.enc\\.yaml$\n    age: age1example\n",
+        )
+        .unwrap();
+
+        let project = ProjectFiles::open(dir.path().to_path_buf());
+        let statuses: Vec<FileStatus> = project
+            .secret_files(&config)
+            .into_iter()
+            .filter_map(|file| file_status(&config, &project, file))
+            .collect();
+
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].file, "foo.yaml");
+        assert!(statuses[0].state == FileState::Plaintext);
+        assert_eq!(statuses[0].rule, 1);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::commands::list_config::print_rule;
+use crate::util::sops_config::{
+    find_creation_rule, path_relative_to_project_root, read_or_create_config,
+};
+use crate::util::sops_structs::{CreationRule, SopsConfig};
+use colored::Colorize;
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Entry point for `which-rule`: shows the creation rule sops would pick for a file, the keys it
+/// would be encrypted for and the 1Password item opsops would decrypt it with
+pub fn which_rule(path: OsString) {
+    let path = Path::new(&path);
+    let config = match read_or_create_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{} {}", "❌ Failed to read SOPS config:".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let relative = path_relative_to_project_root(path);
+    let against = if Path::new(&relative).is_absolute() {
+        "outside the project, sops matches the absolute path"
+    } else {
+        "relative to .sops.yaml"
+    };
+    println!(
+        "{} {} {}",
+        "📄 Matching".cyan(),
+        relative.green(),
+        format!("({})", against).dimmed()
+    );
+
+    let (index, rule) = match find_creation_rule(&config, path) {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            print_skipped(&config.creation_rules);
+            eprintln!(
+                "{}",
+                "❌ No creation rule matches this file, sops will refuse to encrypt it.".red()
+            );
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{} {}", "❌".red(), e);
+            std::process::exit(1);
+        }
+    };
+
+    print_skipped(&config.creation_rules[..index]);
+    println!(
+        "{} {} {}",
+        "🔹 Rule #".yellow(),
+        (index + 1).to_string().yellow(),
+        "matches (the first match wins)".dimmed()
+    );
+    if rule.path_regex.as_deref().unwrap_or("").is_empty() {
+        println!(
+            "{}",
+            "  📂 No path_regex, the rule matches every file".cyan()
+        );
+    }
+    print_rule(&config, rule, "  ");
+
+    print_recipients(&config, rule);
+    print_onepassword_item(&config, rule);
+}
+
+/// The rules before the match, which sops tried first
+fn print_skipped(rules: &[CreationRule]) {
+    for (i, rule) in rules.iter().enumerate() {
+        println!(
+            "{}",
+            format!(
+                "   Rule #{} doesn't match {}",
+                i + 1,
+                rule.path_regex.as_deref().unwrap_or_default()
+            )
+            .dimmed()
+        );
+    }
+}
+
+fn print_recipients(config: &SopsConfig, rule: &CreationRule) {
+    let recipients = config.rule_recipients(rule);
+    if recipients.is_empty() {
+        println!("{}", "⚠️  The rule lists no age recipients.".yellow());
+    } else {
+        println!("{}", "🔑 Encrypted for:".cyan());
+        for key in &recipients {
+            let names = config.recipient_names(key);
+            if names.is_empty() {
+                println!("  - {}", key.green());
+            } else {
+                println!(
+                    "  - {} {}",
+                    key.green(),
+                    format!("({})", names.join(", ")).dimmed()
+                );
+            }
+        }
+    }
+
+    let others = rule.other_key_types();
+    if !others.is_empty() {
+        println!(
+            "{} {}",
+            "ℹ️  Also encrypted for other key types, opsops hands this file to sops:".blue(),
+            others.join(", ")
+        );
+    }
+}
+
+fn print_onepassword_item(config: &SopsConfig, rule: &CreationRule) {
+    let Some(reference) = config.onepassword_reference(Some(rule)) else {
+        println!(
+            "{}",
+            "⚠️  No 1Password item is configured, run `opsops init` to set one.".yellow()
+        );
+        return;
+    };
+
+    let source = match rule.onepassworditem.as_deref().filter(|r| !r.is_empty()) {
+        Some(name) if config.onepassworditems.contains_key(name) => {
+            format!("onepassworditems.{} named by the rule", name)
+        }
+        Some(_) => "the rule's onepassworditem".to_string(),
+        None => "the project's onepassworditem".to_string(),
+    };
+    println!(
+        "{} {} {}",
+        "🔐 1Password key:".cyan(),
+        reference.green(),
+        format!("(from {})", source).dimmed()
+    );
+}
@@ -22,6 +22,23 @@ enum Commands {
     #[command(arg_required_else_help = false)]
     ListConfig {},
 
+    /// Show which creation rule applies to a file and who it is encrypted for
+    #[command(arg_required_else_help = true)]
+    WhichRule {
+        #[arg(
+            value_name = "PATH",
+            help = "Path to the file, it doesn't have to exist"
+        )]
+        path: OsString,
+    },
+
+    /// Check the .sops.yaml for this project
+    #[command(arg_required_else_help = true)]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
     /// Generate an age key pair
     #[command(arg_required_else_help = false)]
     GenerateAgeKey {},
@@ -36,17 +53,243 @@ enum Commands {
     /// Encrypt a file using sops
     #[command(arg_required_else_help = true)]
     Encrypt {
-        #[arg(value_name = "PATH", help = "Path to the file to encrypt")]
-        path: OsString,
+        #[arg(
+            value_name = "PATH",
+            required_unless_present = "all",
+            help = "Path to the file to encrypt"
+        )]
+        path: Option<OsString>,
+
+        #[arg(
+            short,
+            long,
+            value_name = "OUTPUT",
+            help = "Where to write the encrypted file"
+        )]
+        output: Option<OsString>,
+
+        #[arg(long, conflicts_with = "output", help = "Replace the plaintext file")]
+        in_place: bool,
+
+        #[arg(long, help = "Overwrite the output file if it already exists")]
+        force: bool,
+
+        #[arg(
+            long,
+            conflicts_with_all = ["path", "output"],
+            help = "Encrypt every file in the project that matches a creation rule"
+        )]
+        all: bool,
     },
 
-    /// Decrypt a file using sops
+    /// Decrypt a file with the key from 1password
     #[command(arg_required_else_help = true)]
     Decrypt {
-        #[arg(value_name = "PATH", help = "Path to the encrypted file to decrypt")]
+        #[arg(
+            value_name = "PATH",
+            required_unless_present = "all",
+            help = "Path to the encrypted file to decrypt"
+        )]
+        path: Option<OsString>,
+
+        #[arg(
+            long,
+            conflicts_with_all = ["output", "in_place"],
+            help = "Write the plaintext to stdout instead of a file"
+        )]
+        stdout: bool,
+
+        #[arg(
+            short,
+            long,
+            value_name = "OUTPUT",
+            help = "Where to write the plaintext"
+        )]
+        output: Option<OsString>,
+
+        #[arg(long, conflicts_with = "output", help = "Replace the encrypted file")]
+        in_place: bool,
+
+        #[arg(long, help = "Overwrite the output file if it already exists")]
+        force: bool,
+
+        #[arg(
+            long,
+            conflicts_with_all = ["path", "output", "stdout"],
+            help = "Decrypt every encrypted file in the project that matches a creation rule"
+        )]
+        all: bool,
+    },
+
+    /// Print the decrypted contents of a file to stdout
+    #[command(arg_required_else_help = true)]
+    Cat {
+        #[arg(value_name = "PATH", help = "Path to the encrypted file to print")]
         path: OsString,
     },
 
+    /// Show which files matching a creation rule are encrypted, plaintext or drifted
+    #[command(arg_required_else_help = false)]
+    Status {
+        #[arg(
+            long,
+            value_name = "FORMAT",
+            default_value = "table",
+            value_parser = ["table", "json"],
+            help = "Output format"
+        )]
+        format: String,
+    },
+
+    /// Run a command with the decrypted secrets as environment variables
+    #[command(arg_required_else_help = true)]
+    Exec {
+        #[arg(
+            value_name = "PATH",
+            help = "Path to the encrypted dotenv, YAML or JSON file"
+        )]
+        path: OsString,
+
+        #[arg(long, help = "Prefix to add to every variable name")]
+        prefix: Option<String>,
+
+        #[arg(
+            long,
+            help = "Export nested keys as PARENT_CHILD instead of JSON values"
+        )]
+        flatten: bool,
+
+        #[arg(
+            last = true,
+            required = true,
+            value_name = "COMMAND",
+            help = "Command to run"
+        )]
+        command: Vec<OsString>,
+    },
+
+    /// Run a command with the decrypted secrets in a temporary file
+    #[command(arg_required_else_help = true)]
+    ExecFile {
+        #[arg(value_name = "PATH", help = "Path to the encrypted file")]
+        path: OsString,
+
+        #[arg(long, help = "Use a named pipe instead of a regular file")]
+        fifo: bool,
+
+        #[arg(
+            long,
+            help = "File name for the plaintext file (defaults to the source file name)"
+        )]
+        filename: Option<String>,
+
+        #[arg(
+            last = true,
+            required = true,
+            value_name = "COMMAND",
+            help = "Command to run, {} is replaced with the path of the plaintext file"
+        )]
+        command: Vec<OsString>,
+    },
+
+    /// Manage the git pre-commit hook that blocks plaintext secrets
+    #[command(arg_required_else_help = true)]
+    Hook {
+        #[command(subcommand)]
+        command: HookCommands,
+    },
+
+    /// Configure git to show decrypted changes of encrypted files in diffs
+    #[command(arg_required_else_help = false)]
+    GitSetup {},
+
+    /// Merge two versions of an encrypted file, called by git (see git-setup)
+    #[command(arg_required_else_help = true)]
+    MergeDriver {
+        #[arg(value_name = "BASE", help = "The common ancestor (%O)")]
+        base: OsString,
+
+        #[arg(
+            value_name = "OURS",
+            help = "Our version, replaced with the merge result (%A)"
+        )]
+        ours: OsString,
+
+        #[arg(value_name = "THEIRS", help = "Their version (%B)")]
+        theirs: OsString,
+
+        #[arg(value_name = "PATH", help = "Path of the file in the repository (%P)")]
+        path: Option<OsString>,
+    },
+
+    /// Add or remove team members' public keys in .sops.yaml
+    #[command(arg_required_else_help = true)]
+    Recipients {
+        #[command(subcommand)]
+        command: RecipientsCommands,
+    },
+
+    /// Replace an age key with a new one stored in 1Password and re-encrypt everything for it
+    #[command(arg_required_else_help = false)]
+    Rotate {
+        #[arg(
+            long,
+            value_name = "ITEM",
+            help = "Key to rotate, a name from onepassworditems or an op:// reference (defaults to onepassworditem)"
+        )]
+        item: Option<String>,
+
+        #[arg(
+            long,
+            value_name = "TITLE",
+            help = "Title of the 1Password item for the new key"
+        )]
+        title: Option<String>,
+
+        #[arg(long, help = "Show what would change without changing anything")]
+        dry_run: bool,
+    },
+
+    /// Keep keys read from 1Password in memory, so `op` isn't asked every time
+    #[command(arg_required_else_help = false)]
+    Agent {
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "15m",
+            help = "How long keys are kept, like 30s, 15m or 1h"
+        )]
+        ttl: String,
+
+        #[command(subcommand)]
+        command: Option<AgentCommands>,
+    },
+
+    /// Let age and sops decrypt with keys from 1Password through the age-plugin-opsops plugin
+    #[command(arg_required_else_help = true)]
+    AgePlugin {
+        #[command(subcommand)]
+        command: AgePluginCommands,
+    },
+
+    /// Serve the sops KeyService on a Unix socket, backed by keys from 1Password
+    #[command(arg_required_else_help = false)]
+    Keyservice {
+        #[arg(
+            long,
+            value_name = "PATH",
+            help = "Socket to listen on (defaults to keyservice.sock in a private directory)"
+        )]
+        socket: Option<OsString>,
+
+        #[arg(
+            long,
+            value_name = "ITEM",
+            help = "Key to serve, a name from onepassworditems or an op:// reference (defaults to every key in .sops.yaml)"
+        )]
+        item: Vec<String>,
+    },
+
     /// Troubleshoot your current config
     #[command(arg_required_else_help = false)]
     Doctor {},
@@ -54,6 +297,13 @@ enum Commands {
     /// Initialize opsops
     Init {},
 
+    /// Start the user's editor without the Age key, sops runs it as EDITOR during edit
+    #[command(hide = true)]
+    SopsEditor {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<OsString>,
+    },
+
     /// Generate shell completions and man pages
     #[command(arg_required_else_help = false, hide = true)]
     GenerateDocs {
@@ -63,6 +313,91 @@ enum Commands {
     },
 }
 
+#[derive(Debug, Subcommand)]
+enum ConfigCommands {
+    /// Report every problem in .sops.yaml with its line and column, exits with 1 if there are any
+    Validate {},
+}
+
+#[derive(Debug, Subcommand)]
+enum AgentCommands {
+    /// Make the running agent forget every key
+    Lock {},
+
+    /// Show the keys the running agent holds and when they expire
+    Status {},
+}
+
+#[derive(Debug, Subcommand)]
+enum AgePluginCommands {
+    /// Print the plugin identity for a key, for an age identity file like SOPS_AGE_KEY_FILE
+    Identity {
+        #[arg(
+            long,
+            value_name = "ITEM",
+            help = "A name from onepassworditems or an op:// reference (defaults to onepassworditem)"
+        )]
+        item: Option<String>,
+    },
+
+    /// Link age-plugin-opsops to opsops so age and sops can run it
+    Install {
+        #[arg(
+            long,
+            value_name = "DIR",
+            help = "Directory on the PATH for the link (defaults to the directory of opsops)"
+        )]
+        dir: Option<OsString>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum HookCommands {
+    /// Install the pre-commit hook in this repository
+    Install {
+        #[arg(long, help = "Replace an existing pre-commit hook")]
+        force: bool,
+    },
+
+    /// Check the staged files, this is what the hook runs
+    Run {},
+}
+
+#[derive(Debug, Subcommand)]
+enum RecipientsCommands {
+    /// Add a public key to creation rules and let it decrypt their files
+    Add {
+        #[arg(value_name = "KEY", help = "Age public key (age1...)")]
+        key: String,
+
+        #[arg(
+            long,
+            value_name = "RULE",
+            help = "Rule number or path_regex of the rule to change (defaults to every rule)"
+        )]
+        rule: Option<String>,
+
+        #[arg(long, value_name = "NAME", help = "Name to remember the key by")]
+        name: Option<String>,
+    },
+
+    /// Remove a public key from creation rules and re-encrypt their files without it
+    Remove {
+        #[arg(
+            value_name = "RECIPIENT",
+            help = "Age public key or the name it was added with"
+        )]
+        recipient: String,
+
+        #[arg(
+            long,
+            value_name = "RULE",
+            help = "Rule number or path_regex of the rule to change (defaults to every rule)"
+        )]
+        rule: Option<String>,
+    },
+}
+
 impl Cli {
     /// Generate man pages and shell completions
     fn generate_docs(output_dir: &str) -> io::Result<()> {
@@ -110,15 +445,86 @@ impl Cli {
 }
 
 fn main() -> io::Result<()> {
+    // age and sops run the plugin through a link named age-plugin-opsops
+    if commands::age_plugin::invoked_as_plugin() {
+        commands::age_plugin::run_plugin();
+    }
+
     let args = Cli::parse();
 
     match args.command {
         Commands::ListConfig {} => commands::list_config::list_config(),
+        Commands::WhichRule { path } => commands::which_rule::which_rule(path),
+        Commands::Config { command } => match command {
+            ConfigCommands::Validate {} => commands::config_validate::config_validate(),
+        },
         Commands::GenerateAgeKey {} => commands::generate_age_key::generate_age_key(),
         Commands::Edit { path } => commands::edit::edit(path),
-        Commands::Encrypt { path } => commands::encrypt::encrypt(path),
-        Commands::Decrypt { path } => commands::decrypt::decrypt(path),
+        Commands::Encrypt {
+            path,
+            output,
+            in_place,
+            force,
+            all,
+        } => commands::encrypt::encrypt(path, output, in_place, force, all),
+        Commands::Decrypt {
+            path,
+            stdout,
+            output,
+            in_place,
+            force,
+            all,
+        } => commands::decrypt::decrypt(path, stdout, output, in_place, force, all),
+        Commands::Cat { path } => commands::cat::cat(path),
+        Commands::Status { format } => commands::status::status(format),
         Commands::Init {} => commands::init::init(),
+        Commands::Exec {
+            path,
+            prefix,
+            flatten,
+            command,
+        } => commands::exec::exec(path, command, prefix, flatten),
+        Commands::ExecFile {
+            path,
+            fifo,
+            filename,
+            command,
+        } => commands::exec_file::exec_file(path, command, fifo, filename),
+        Commands::Hook { command } => match command {
+            HookCommands::Install { force } => commands::hook::hook_install(force),
+            HookCommands::Run {} => commands::hook::hook_run(),
+        },
+        Commands::GitSetup {} => commands::git_setup::git_setup(),
+        Commands::MergeDriver {
+            base,
+            ours,
+            theirs,
+            path,
+        } => commands::merge_driver::merge_driver(base, ours, theirs, path),
+        Commands::Recipients { command } => match command {
+            RecipientsCommands::Add { key, rule, name } => {
+                commands::recipients::recipients_add(key, rule, name)
+            }
+            RecipientsCommands::Remove { recipient, rule } => {
+                commands::recipients::recipients_remove(recipient, rule)
+            }
+        },
+        Commands::Rotate {
+            item,
+            title,
+            dry_run,
+        } => commands::rotate::rotate(item, title, dry_run),
+        Commands::Agent { ttl, command } => match command {
+            None => commands::agent::agent(ttl),
+            Some(AgentCommands::Lock {}) => commands::agent::agent_lock(),
+            Some(AgentCommands::Status {}) => commands::agent::agent_status(),
+        },
+        Commands::AgePlugin { command } => match command {
+            AgePluginCommands::Identity { item } => commands::age_plugin::age_plugin_identity(item),
+            AgePluginCommands::Install { dir } => commands::age_plugin::age_plugin_install(dir),
+        },
+        Commands::Keyservice { socket, item } => commands::keyservice::keyservice(socket, item),
+        Commands::SopsEditor { args } => commands::sops_editor::sops_editor(args),
         Commands::Doctor {} => commands::doctor::doctor(),
         Commands::GenerateDocs { dir } => Cli::generate_docs(&dir)?,
     }
@@ -0,0 +1,126 @@
+use super::private_socket::{owned_by_user, socket_dir};
+use super::secret_memory::secret_key;
+use age::secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+/// Overrides where the agent listens, for both the agent and its clients
+pub const SOCKET_ENV: &str = "OPSOPS_AGENT_SOCK";
+
+/// How long a client waits for the agent before falling back to 1Password
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A request to the agent, one JSON object per line
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    /// The private key cached for a 1Password reference
+    Get {
+        reference: String,
+    },
+    /// The public key of a cached key, without handing out the private key
+    PublicKey {
+        reference: String,
+    },
+    /// Cache a key read from 1Password
+    Add {
+        reference: String,
+        key: Zeroizing<String>,
+    },
+    /// Forget every cached key
+    Lock,
+    Status,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    Key {
+        key: Zeroizing<String>,
+    },
+    PublicKey {
+        public_key: String,
+    },
+    NotCached,
+    Added,
+    Locked {
+        forgotten: usize,
+    },
+    Status {
+        ttl_seconds: u64,
+        keys: Vec<CachedKey>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CachedKey {
+    pub reference: String,
+    pub public_key: String,
+    pub expires_in_seconds: u64,
+}
+
+/// The agent's socket: `$OPSOPS_AGENT_SOCK`, else in a private directory under
+/// `$XDG_RUNTIME_DIR` or the temporary directory
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = std::env::var_os(SOCKET_ENV).filter(|p| !p.is_empty()) {
+        return PathBuf::from(path);
+    }
+    socket_dir().join("agent.sock")
+}
+
+/// Send a request to the running agent. Returns None if no agent is running, or if the socket
+/// belongs to another user, as keys must not be handed to someone else's process.
+pub fn agent_request(request: &Request) -> Option<Response> {
+    let path = socket_path();
+    if !owned_by_user(&path) {
+        return None;
+    }
+
+    let mut stream = UnixStream::connect(&path).ok()?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+
+    // Both lines may hold a private key
+    let mut line = Zeroizing::new(serde_json::to_string(request).ok()?);
+    line.push('\n');
+    stream.write_all(line.as_bytes()).ok()?;
+
+    let mut response = Zeroizing::new(String::new());
+    BufReader::new(stream).read_line(&mut response).ok()?;
+    serde_json::from_str(&response).ok()
+}
+
+/// The private key the agent holds for a 1Password reference
+pub fn cached_key(reference: &str) -> Option<SecretString> {
+    match agent_request(&Request::Get {
+        reference: reference.to_string(),
+    })? {
+        Response::Key { key } => Some(secret_key(key.to_string())),
+        _ => None,
+    }
+}
+
+/// The public key of a key the agent holds for a 1Password reference
+pub fn cached_public_key(reference: &str) -> Option<String> {
+    match agent_request(&Request::PublicKey {
+        reference: reference.to_string(),
+    })? {
+        Response::PublicKey { public_key } => Some(public_key),
+        _ => None,
+    }
+}
+
+/// Hand a key read from 1Password to the agent, if one is running
+pub fn cache_key(reference: &str, key: &SecretString) {
+    agent_request(&Request::Add {
+        reference: reference.to_string(),
+        key: Zeroizing::new(key.expose_secret().to_string()),
+    });
+}
@@ -0,0 +1,85 @@
+use colored::Colorize;
+use std::path::Path;
+
+/// What happened to a single file during `--all`
+pub enum BulkStatus {
+    Changed(String),
+    UpToDate,
+    Failed(String),
+}
+
+pub struct BulkResult {
+    pub file: String,
+    pub status: BulkStatus,
+}
+
+/// Returns true if the output exists and was written after the source last changed
+pub fn is_up_to_date(source: &Path, output: &Path) -> bool {
+    let modified = |p: &Path| p.metadata().and_then(|m| m.modified()).ok();
+    match (modified(source), modified(output)) {
+        (Some(source), Some(output)) => output >= source,
+        _ => false,
+    }
+}
+
+/// Give the output the modification time of its source, so the pair counts as in sync and
+/// decrypting a file doesn't make its plaintext look like it changed since it was encrypted
+pub fn sync_modified(source: &Path, output: &Path) -> std::io::Result<()> {
+    let modified = source.metadata()?.modified()?;
+    std::fs::File::options()
+        .write(true)
+        .open(output)?
+        .set_modified(modified)
+}
+
+/// Print a table of every processed file followed by the totals.
+/// Returns true if any file failed.
+pub fn print_summary(changed_label: &str, results: &[BulkResult]) -> bool {
+    if results.is_empty() {
+        println!(
+            "{}",
+            "ℹ️ No files matched the creation rules in .sops.yaml".blue()
+        );
+        return false;
+    }
+
+    let width = results
+        .iter()
+        .map(|r| r.file.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+
+    println!();
+    println!(
+        "{:<width$}  {:<10}  {}",
+        "FILE".bold(),
+        "RESULT".bold(),
+        "DETAILS".bold()
+    );
+    let (mut changed, mut up_to_date, mut failed) = (0, 0, 0);
+    for result in results {
+        let (label, details) = match &result.status {
+            BulkStatus::Changed(details) => {
+                changed += 1;
+                (format!("{:<10}", changed_label).green(), details.as_str())
+            }
+            BulkStatus::UpToDate => {
+                up_to_date += 1;
+                (format!("{:<10}", "up to date").blue(), "")
+            }
+            BulkStatus::Failed(error) => {
+                failed += 1;
+                (format!("{:<10}", "failed").red(), error.as_str())
+            }
+        };
+        println!("{:<width$}  {}  {}", result.file, label, details);
+    }
+
+    println!();
+    println!(
+        "{} {}, {} up to date, {} failed",
+        changed, changed_label, up_to_date, failed
+    );
+    failed > 0
+}
@@ -0,0 +1,284 @@
+use std::fs;
+use std::path::Path;
+
+/// Stands for a regex `.*` while translating, as it may also match across directories
+const ANY_PATH: char = '\0';
+
+/// Lines between these markers in `.gitattributes` are owned by opsops and rewritten on setup
+const BLOCK_START: &str = "# BEGIN opsops";
+const BLOCK_END: &str = "# END opsops";
+
+/// Replace the opsops block in a `.gitattributes` file, keeping everything else as it is
+pub fn write_gitattributes_block(path: &Path, lines: &[String]) -> Result<(), String> {
+    let existing = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+
+    let mut kept = Vec::new();
+    let mut in_block = false;
+    for line in existing.lines() {
+        match line.trim() {
+            BLOCK_START => in_block = true,
+            BLOCK_END => in_block = false,
+            _ if !in_block => kept.push(line),
+            _ => {}
+        }
+    }
+    while kept.last().is_some_and(|l| l.trim().is_empty()) {
+        kept.pop();
+    }
+
+    let mut contents = kept.join("\n");
+    if !contents.is_empty() {
+        contents.push_str("\n\n");
+    }
+    contents.push_str(BLOCK_START);
+    contents.push('\n');
+    for line in lines {
+        contents.push_str(line);
+        contents.push('\n');
+    }
+    contents.push_str(BLOCK_END);
+    contents.push('\n');
+
+    fs::write(path, contents).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Translate a creation rule's `path_regex` into gitattributes patterns that together match
+/// every path the regex matches. Only the common subset is understood: anchors, `.*`, `.`,
+/// escapes, `x?`, `(a|b)` and character classes. Returns None for anything else, which the
+/// patterns could only approximate.
+pub fn regex_to_globs(regex: &str) -> Option<Vec<String>> {
+    let chars: Vec<char> = regex.chars().collect();
+    let mut globs = Vec::new();
+
+    for branch in split_alternatives(&chars) {
+        let anchored_start = branch.first() == Some(&'^');
+        let anchored_end =
+            branch.last() == Some(&'$') && branch.len() >= 2 && branch[branch.len() - 2] != '\\';
+        let body = &branch[usize::from(anchored_start)..branch.len() - usize::from(anchored_end)];
+
+        for variant in expand(body)? {
+            for glob in path_globs(variant, anchored_start, anchored_end) {
+                if !globs.contains(&glob) {
+                    globs.push(glob);
+                }
+            }
+        }
+    }
+    Some(globs)
+}
+
+/// Turn an expanded regex alternative into gitattributes patterns for the whole path
+fn path_globs(mut glob: String, anchored_start: bool, anchored_end: bool) -> Vec<String> {
+    // An unanchored regex matches anywhere in the path
+    if !anchored_end && !glob.ends_with(ANY_PATH) {
+        glob.push(ANY_PATH);
+    }
+    if !anchored_start && !glob.starts_with(ANY_PATH) {
+        glob.insert(0, ANY_PATH);
+    }
+
+    // After a directory `.*` is `**/*`, which also matches in subdirectories. Elsewhere a `*`
+    // stops at the next slash, so a second pattern continues into subdirectories.
+    let mut variants = vec!["/".to_string()];
+    for c in glob.chars() {
+        let atom: &[&str] = match c {
+            ANY_PATH if variants[0].ends_with('/') => &["**/*"],
+            ANY_PATH => &["*", "*/**/*"],
+            _ => &[],
+        };
+        variants = if atom.is_empty() {
+            variants.into_iter().map(|v| v + &c.to_string()).collect()
+        } else {
+            variants
+                .iter()
+                .flat_map(|v| atom.iter().map(move |a| format!("{}{}", v, a)))
+                .collect()
+        };
+    }
+
+    variants
+        .into_iter()
+        .map(|glob| match glob.strip_prefix("/**/") {
+            // A pattern without a slash already matches at every level
+            Some(rest) if !rest.contains('/') => rest.to_string(),
+            // A slash inside the pattern anchors it to the root anyway
+            _ if glob[1..].contains('/') => glob[1..].to_string(),
+            _ => glob,
+        })
+        .collect()
+}
+
+/// Expand a regex body into the glob of every alternative it describes
+fn expand(chars: &[char]) -> Option<Vec<String>> {
+    let mut variants = vec![String::new()];
+    let mut i = 0;
+
+    while i < chars.len() {
+        // The alternatives for the next atom
+        let (atom, next): (Vec<String>, usize) = match chars[i] {
+            '.' if matches!(chars.get(i + 1), Some('*') | Some('+')) => {
+                (vec![ANY_PATH.to_string()], i + 2)
+            }
+            // `?` matches any character but a slash
+            '.' => (vec!["?".to_string(), "/".to_string()], i + 1),
+            '\\' => {
+                let escaped = *chars.get(i + 1)?;
+                if escaped.is_alphanumeric() {
+                    // \d, \w, \s and friends have no glob equivalent
+                    return None;
+                }
+                (vec![glob_literal(escaped)], i + 2)
+            }
+            '[' => {
+                let end = i + chars[i..].iter().position(|c| *c == ']')?;
+                let class: String = chars[i + 1..end].iter().collect();
+                // Nested classes like `[[:alpha:]]` and escapes like `\d` differ in globs
+                if class.contains('[') || class.contains('\\') {
+                    return None;
+                }
+                match class.strip_prefix('^') {
+                    // A negated class matches a slash in a regex, never in a glob
+                    Some(negated) if !negated.contains('/') => {
+                        (vec![format!("[!{}]", negated), "/".to_string()], end + 1)
+                    }
+                    Some(negated) => (vec![format!("[!{}]", negated)], end + 1),
+                    None => (vec![format!("[{}]", class)], end + 1),
+                }
+            }
+            '(' => {
+                let end = matching_paren(chars, i)?;
+                let inner = &chars[i + 1..end];
+                let inner = inner.strip_prefix(&['?', ':']).unwrap_or(inner);
+                let mut alternatives = Vec::new();
+                for branch in split_alternatives(inner) {
+                    alternatives.extend(expand(branch)?);
+                }
+                (alternatives, end + 1)
+            }
+            '*' | '+' | '{' | '}' | '|' | ')' | '^' | '$' => return None,
+            c => (vec![glob_literal(c)], i + 1),
+        };
+
+        // A trailing `?` makes the atom optional
+        let (atom, next) = if chars.get(next) == Some(&'?') {
+            let mut optional = atom;
+            optional.push(String::new());
+            (optional, next + 1)
+        } else {
+            (atom, next)
+        };
+        // Repeating a class that excludes `/` stays within one path segment, like `*`
+        let (atom, next) = match chars.get(next) {
+            Some('*') | Some('+') if atom.len() == 1 && atom[0].starts_with("[!/") => {
+                (vec!["*".to_string()], next + 1)
+            }
+            Some('*') | Some('+') | Some('{') => return None,
+            _ => (atom, next),
+        };
+
+        variants = variants
+            .iter()
+            .flat_map(|prefix| atom.iter().map(move |a| format!("{}{}", prefix, a)))
+            .collect();
+        i = next;
+    }
+
+    Some(variants)
+}
+
+fn glob_literal(c: char) -> String {
+    match c {
+        '*' | '?' | '[' | ']' | '\\' => format!("\\{}", c),
+        c => c.to_string(),
+    }
+}
+
+fn matching_paren(chars: &[char], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = open;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Split a group body on its top level `|`
+fn split_alternatives(chars: &[char]) -> Vec<&[char]> {
+    let mut branches = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            '|' if depth == 0 => {
+                branches.push(&chars[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    branches.push(&chars[start..]);
+    branches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_path_regexes() {
+        let cases: &[(&str, &[&str])] = &[
+            (r"^secrets/.*\.yaml$", &["secrets/**/*.yaml"]),
+            (r"\.enc\.json$", &["*.enc.json"]),
+            (r"^config\.ya?ml$", &["/config.yaml", "/config.yml"]),
+            (r"^(prod|dev)/", &["prod/**/*", "dev/**/*"]),
+            (r"^prod/[^/]*\.env$", &["prod/*.env"]),
+            // `.` also matches a slash
+            (r"^a.b$", &["/a?b", "a/b"]),
+            // `.*` inside a name continues into subdirectories
+            (r"^sec.*\.yaml$", &["/sec*.yaml", "sec*/**/*.yaml"]),
+            // A negated class also matches a slash
+            (r"^x[^a]y$", &["/x[!a]y", "x/y"]),
+            (r"secret", &["*secret*", "**/*secret*/**/*"]),
+        ];
+        for (regex, expected) in cases {
+            let expected: Vec<String> = expected.iter().map(|g| g.to_string()).collect();
+            assert_eq!(regex_to_globs(regex), Some(expected), "{}", regex);
+        }
+    }
+
+    #[test]
+    fn refuses_what_globs_cant_express() {
+        for regex in [
+            r"^\d+\.yaml$",
+            r"^[[:alpha:]]\.yaml$",
+            r"^[\w]\.yaml$",
+            r"^a{2}$",
+            r"^keys/[a-z]+$",
+            r"^[^.]+$",
+            r"^(a|b)*$",
+            r"^a$|b$^",
+        ] {
+            assert_eq!(regex_to_globs(regex), None, "{}", regex);
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! The gRPC KeyService of sops (`keyservice/keyservice.proto` in the sops repository), written
+//! out by hand as tonic-build would generate it, so building opsops doesn't need protoc.
+//! Only age keys are modelled, requests for other key types arrive without a key.
+
+use std::sync::Arc;
+use tonic::codegen::{BoxFuture, Context, Poll, Service, StdError, http};
+use tonic::{Request, Response, Status};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AgeKey {
+    #[prost(string, tag = "1")]
+    pub recipient: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Key {
+    #[prost(oneof = "KeyType", tags = "6")]
+    pub key_type: Option<KeyType>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum KeyType {
+    #[prost(message, tag = "6")]
+    AgeKey(AgeKey),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct EncryptRequest {
+    #[prost(message, optional, tag = "1")]
+    pub key: Option<Key>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub plaintext: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct EncryptResponse {
+    #[prost(bytes = "vec", tag = "1")]
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DecryptRequest {
+    #[prost(message, optional, tag = "1")]
+    pub key: Option<Key>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DecryptResponse {
+    #[prost(bytes = "vec", tag = "1")]
+    pub plaintext: Vec<u8>,
+}
+
+impl EncryptRequest {
+    pub fn age_recipient(&self) -> Option<&str> {
+        age_recipient(&self.key)
+    }
+}
+
+impl DecryptRequest {
+    pub fn age_recipient(&self) -> Option<&str> {
+        age_recipient(&self.key)
+    }
+}
+
+fn age_recipient(key: &Option<Key>) -> Option<&str> {
+    match key.as_ref()?.key_type.as_ref()? {
+        KeyType::AgeKey(key) => Some(&key.recipient),
+    }
+}
+
+/// The methods of the service
+#[tonic::async_trait]
+pub trait KeyService: Send + Sync + 'static {
+    async fn encrypt(
+        &self,
+        request: Request<EncryptRequest>,
+    ) -> Result<Response<EncryptResponse>, Status>;
+
+    async fn decrypt(
+        &self,
+        request: Request<DecryptRequest>,
+    ) -> Result<Response<DecryptResponse>, Status>;
+}
+
+/// Routes gRPC requests to a `KeyService`
+pub struct KeyServiceServer<T> {
+    inner: Arc<T>,
+}
+
+impl<T> KeyServiceServer<T> {
+    pub fn new(inner: T) -> Self {
+        KeyServiceServer {
+            inner: Arc::new(inner),
+        }
+    }
+}
+
+impl<T> Clone for KeyServiceServer<T> {
+    fn clone(&self) -> Self {
+        KeyServiceServer {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> tonic::server::NamedService for KeyServiceServer<T> {
+    // keyservice.proto has no package
+    const NAME: &'static str = "KeyService";
+}
+
+struct EncryptSvc<T>(Arc<T>);
+
+impl<T: KeyService> tonic::server::UnaryService<EncryptRequest> for EncryptSvc<T> {
+    type Response = EncryptResponse;
+    type Future = BoxFuture<Response<EncryptResponse>, Status>;
+
+    fn call(&mut self, request: Request<EncryptRequest>) -> Self::Future {
+        let inner = Arc::clone(&self.0);
+        Box::pin(async move { inner.encrypt(request).await })
+    }
+}
+
+struct DecryptSvc<T>(Arc<T>);
+
+impl<T: KeyService> tonic::server::UnaryService<DecryptRequest> for DecryptSvc<T> {
+    type Response = DecryptResponse;
+    type Future = BoxFuture<Response<DecryptResponse>, Status>;
+
+    fn call(&mut self, request: Request<DecryptRequest>) -> Self::Future {
+        let inner = Arc::clone(&self.0);
+        Box::pin(async move { inner.decrypt(request).await })
+    }
+}
+
+impl<T, B> Service<http::Request<B>> for KeyServiceServer<T>
+where
+    T: KeyService,
+    B: tonic::codegen::Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let inner = Arc::clone(&self.inner);
+        match request.uri().path() {
+            "/KeyService/Encrypt" => Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::default());
+                Ok(grpc.unary(EncryptSvc(inner), request).await)
+            }),
+            "/KeyService/Decrypt" => Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::default());
+                Ok(grpc.unary(DecryptSvc(inner), request).await)
+            }),
+            _ => Box::pin(async move { Ok(Status::unimplemented("unknown method").into_http()) }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    #[test]
+    fn age_keys_are_field_6_of_key() {
+        let key = Key {
+            key_type: Some(KeyType::AgeKey(AgeKey {
+                recipient: "age1x".to_string(),
+            })),
+        };
+        // Field 6 (age_key) holding field 1 (recipient), both length delimited
+        assert_eq!(key.encode_to_vec(), b"\x32\x07\x0a\x05age1x");
+        assert_eq!(Key::decode(&b"\x32\x07\x0a\x05age1x"[..]).unwrap(), key);
+
+        let request = DecryptRequest {
+            key: Some(key),
+            ciphertext: b"enc".to_vec(),
+        };
+        assert_eq!(
+            request.encode_to_vec(),
+            b"\x0a\x09\x32\x07\x0a\x05age1x\x12\x03enc"
+        );
+    }
+}
@@ -1,7 +1,21 @@
+pub mod agent;
+pub mod bulk;
 pub mod find_project_root;
+pub mod gitattributes;
+pub mod keyservice;
 pub mod op;
 pub mod op_key;
+pub mod output_path;
+pub mod private_socket;
+pub mod project_files;
+pub mod secret_memory;
+pub mod sops_cipher;
 pub mod sops_command;
 pub mod sops_config;
+pub mod sops_decrypt;
+pub mod sops_document;
 pub mod sops_status;
 pub mod sops_structs;
+pub mod tree_merge;
+pub mod yaml_edit;
+pub mod yaml_tree;
@@ -1,5 +1,7 @@
-use serde::Deserialize;
-use std::process::Command;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use zeroize::Zeroizing;
 
 #[derive(Debug, Deserialize)]
 pub struct ItemField {
@@ -18,29 +20,18 @@ pub struct ListItem {
 
 #[derive(Debug, Deserialize)]
 pub struct Vault {
-    id: String,
     name: String,
-    content_version: u32,
-    created_at: String,
-    updated_at: String,
-    items: u32,
 }
 
 /// Represents the category of a 1Password item.
 pub enum OpCategory {
-    Login,
     Password,
-    Identity,
-    Server,
 }
 
 impl OpCategory {
     pub fn as_str(&self) -> &'static str {
         match self {
-            OpCategory::Login => "login",
             OpCategory::Password => "password",
-            OpCategory::Identity => "identity",
-            OpCategory::Server => "server",
         }
     }
 }
@@ -49,24 +40,17 @@ impl OpCategory {
 pub struct OpItemField {
     pub section: Option<String>,
     pub field: String,
+    /// `STRING`, `CONCEALED` and the other field types of 1Password item templates
     pub field_type: Option<String>,
-    pub value: String,
+    pub value: Zeroizing<String>,
 }
 
 impl OpItemField {
-    fn to_flag(&self) -> String {
-        let mut flag = String::new();
-        if let Some(section) = &self.section {
-            flag.push_str(section);
-            flag.push('.');
-        }
-        flag.push_str(&self.field);
-        if let Some(field_type) = &self.field_type {
-            flag.push_str(&format!("[{}]", field_type));
-        }
-        flag.push('=');
-        flag.push_str(&self.value);
-        flag
+    fn section_template(&self) -> Option<SectionTemplate<'_>> {
+        self.section.as_deref().map(|name| SectionTemplate {
+            id: name,
+            label: name,
+        })
     }
 }
 
@@ -78,59 +62,100 @@ pub struct OpItem {
     pub(crate) fields: Vec<OpItemField>,
 }
 
-pub fn op_item_create(item: OpItem) {
-    let mut cmd = Command::new("op");
+/// The JSON item template `op item create --template` reads
+#[derive(Serialize)]
+struct ItemTemplate<'a> {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sections: Vec<SectionTemplate<'a>>,
+    fields: Vec<FieldTemplate<'a>>,
+}
 
-    cmd.arg("item")
-        .arg("create")
-        .arg("--vault")
-        .arg(&item.vault)
-        .arg("--title")
-        .arg(&item.title)
-        .arg("--category")
-        .arg(item.category.as_str()); // implement as_str on OpCategory
-
-    for field in item.fields {
-        let field_str = match (&field.section, &field.field_type) {
-            (Some(section), Some(ftype)) => {
-                format!("{}.{}[{}]={}", section, field.field, ftype, field.value)
-            }
-            (Some(section), None) => {
-                format!("{}.{}={}", section, field.field, field.value)
-            }
-            (None, Some(ftype)) => {
-                format!("{}[{}]={}", field.field, ftype, field.value)
-            }
-            (None, None) => {
-                format!("{}={}", field.field, field.value)
-            }
-        };
-        cmd.arg(field_str);
-    }
+#[derive(Serialize, PartialEq)]
+struct SectionTemplate<'a> {
+    id: &'a str,
+    label: &'a str,
+}
+
+#[derive(Serialize)]
+struct FieldTemplate<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    section: Option<SectionTemplate<'a>>,
+    label: &'a str,
+    #[serde(rename = "type")]
+    field_type: &'a str,
+    value: &'a str,
+}
 
-    let status = cmd.status().expect("failed to run `op` command");
+/// Creates the item. Field values may be secrets, so they are handed to `op` as a template on
+/// its stdin rather than as arguments, which every process can read from `/proc/<pid>/cmdline`.
+pub fn op_item_create(item: OpItem) -> Result<(), String> {
+    let template = item_template(&item)?;
+
+    let mut child = Command::new("op")
+        .args(create_args(&item))
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to execute 1Password CLI: {}", e))?;
+    let written = child
+        .stdin
+        .take()
+        .map_or(Ok(()), |mut stdin| stdin.write_all(&template));
+    let status = child
+        .wait()
+        .map_err(|e| format!("Failed to execute 1Password CLI: {}", e))?;
+    written.map_err(|e| format!("Failed to hand the item to 1Password CLI: {}", e))?;
 
     if !status.success() {
-        eprintln!("Failed to create item in 1Password");
+        return Err("Failed to create item in 1Password".to_string());
     }
+    Ok(())
 }
 
-pub fn op_item_get(item_name: &str, field: &str) -> Option<String> {
-    let output = Command::new("op")
-        .arg("item")
-        .arg("get")
-        .arg(item_name)
-        .arg("--field")
-        .arg(field)
-        .output()
-        .ok()?;
+/// The arguments of `op item create`, everything but the field values
+fn create_args(item: &OpItem) -> Vec<&str> {
+    vec![
+        "item",
+        "create",
+        "--vault",
+        &item.vault,
+        "--title",
+        &item.title,
+        "--category",
+        item.category.as_str(),
+        "--template",
+        "/dev/stdin",
+    ]
+}
 
-    if output.status.success() {
-        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
-    } else {
-        eprintln!("Error: {}", String::from_utf8_lossy(&output.stderr));
-        None
+/// The fields of the item as a JSON template, wiped once it is written to `op`
+fn item_template(item: &OpItem) -> Result<Zeroizing<Vec<u8>>, String> {
+    let mut sections = Vec::new();
+    for field in &item.fields {
+        if let Some(section) = field.section_template()
+            && !sections.contains(&section)
+        {
+            sections.push(section);
+        }
     }
+    let template = ItemTemplate {
+        sections,
+        fields: item
+            .fields
+            .iter()
+            .map(|field| FieldTemplate {
+                section: field.section_template(),
+                label: &field.field,
+                field_type: field.field_type.as_deref().unwrap_or("STRING"),
+                value: &field.value,
+            })
+            .collect(),
+    };
+
+    // Sized up front, so growing the buffer leaves no copies of the values behind
+    let mut json = Zeroizing::new(Vec::with_capacity(4096));
+    serde_json::to_writer(&mut *json, &template)
+        .map_err(|e| format!("Failed to build the 1Password item: {}", e))?;
+    Ok(json)
 }
 
 pub fn get_vaults() -> Option<Vec<String>> {
@@ -221,3 +246,79 @@ pub fn get_fields(item: &String, vault: &String) -> Option<Vec<String>> {
         None
     }
 }
+
+/// Split `op://<vault>/<item>/<field>` into its parts
+pub fn split_reference(reference: &str) -> Result<(&str, &str, &str), String> {
+    let parts: Vec<&str> = reference
+        .strip_prefix("op://")
+        .map(|r| r.splitn(3, '/').collect())
+        .unwrap_or_default();
+
+    match parts[..] {
+        [vault, item, field] if !vault.is_empty() && !item.is_empty() && !field.is_empty() => {
+            Ok((vault, item, field))
+        }
+        _ => Err(format!(
+            "Invalid 1Password reference '{}', expected op://<vault>/<item>/<field>",
+            reference
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "AGE-SECRET-KEY-1TESTTESTTEST";
+
+    fn key_item() -> OpItem {
+        OpItem {
+            vault: "Private".to_string(),
+            title: "opsops".to_string(),
+            category: OpCategory::Password,
+            fields: vec![
+                OpItemField {
+                    section: None,
+                    field: "Public Key".to_string(),
+                    field_type: Some("STRING".to_string()),
+                    value: Zeroizing::new("age1test".to_string()),
+                },
+                OpItemField {
+                    section: Some("keys".to_string()),
+                    field: "Private Key".to_string(),
+                    field_type: Some("CONCEALED".to_string()),
+                    value: Zeroizing::new(SECRET.to_string()),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn field_values_stay_off_the_command_line() {
+        let item = key_item();
+        let args = create_args(&item);
+        assert!(args.iter().all(|arg| !arg.contains(SECRET)), "{:?}", args);
+        assert!(args.windows(2).any(|w| w == ["--template", "/dev/stdin"]));
+    }
+
+    #[test]
+    fn template_holds_the_fields() {
+        let template = item_template(&key_item()).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&template).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "sections": [{"id": "keys", "label": "keys"}],
+                "fields": [
+                    {"label": "Public Key", "type": "STRING", "value": "age1test"},
+                    {
+                        "section": {"id": "keys", "label": "keys"},
+                        "label": "Private Key",
+                        "type": "CONCEALED",
+                        "value": SECRET
+                    }
+                ]
+            })
+        );
+    }
+}
@@ -1,53 +1,331 @@
-use crate::util::sops_config::read_or_create_config;
+use crate::util::agent::{cache_key, cached_key, cached_public_key};
+use crate::util::secret_memory::secret_key;
+use crate::util::sops_config::{find_creation_rule, read_or_create_config};
+use crate::util::sops_document::file_recipients;
+use crate::util::sops_structs::SopsConfig;
+use age::secrecy::{ExposeSecret, SecretString};
+use age::x25519::Identity;
 use colored::Colorize;
+use std::ffi::OsString;
+use std::path::Path;
 use std::process::Command;
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+/// Field of a 1Password item that holds the public key next to the private one
+const PUBLIC_KEY_FIELD: &str = "Public Key";
+
+/// Retrieves the Age key for a file from 1Password.
+/// For an encrypted file this is the configured key matching one of its recipients, for
+/// anything else the reference of the creation rule matching the file or the project wide one.
+/// Returns the key, zeroized when dropped, if successful, or an error message if not
+pub fn get_age_key_from_1password(path: &Path) -> Result<SecretString, String> {
+    if let Ok(recipients) = file_recipients(path)
+        && !recipients.is_empty()
+    {
+        return get_age_key_for_recipients(path, &recipients);
+    }
 
-/// Retrieves the Age key from 1Password using the reference stored in .sops.yaml
-/// Returns the key as a string if successful, or an error message if not
-pub fn get_age_key_from_1password() -> Result<String, String> {
     // Read the SOPS config to get the 1Password reference
-    let config =
-        read_or_create_config().map_err(|e| format!("Failed to read SOPS config: {}", e))?;
-
-    // Check if onepassworditem is set
-    if config.onepassworditem.is_empty() {
-        return Err(
-            "No 1Password reference found in .sops.yaml. Run 'opsops init' to configure."
-                .to_string(),
+    let config = read_config()?;
+    let Some(op_reference) = rule_reference(&config, path)? else {
+        return Err(no_reference_error());
+    };
+
+    read_age_key(&op_reference)
+}
+
+/// Fetches the first configured key whose public key is one of the recipients of a file.
+/// Public keys are read from the item's "Public Key" field, so private keys that can't help
+/// are never fetched.
+pub fn get_age_key_for_recipients(
+    path: &Path,
+    recipients: &[String],
+) -> Result<SecretString, String> {
+    let config = read_config()?;
+
+    // The reference of the file's own rule is the most likely to match
+    let preferred = rule_reference(&config, path).ok().flatten();
+    let mut references = config.onepassword_references();
+    if let Some(preferred) = preferred {
+        references.retain(|r| *r != preferred);
+        references.insert(0, preferred);
+    }
+    find_age_key(&references, recipients)
+}
+
+/// Fetches the key for one of `recipients` from the given 1Password references
+pub fn find_age_key(references: &[String], recipients: &[String]) -> Result<SecretString, String> {
+    OnePassword::cli().find_age_key(references, recipients)
+}
+
+/// The public key of the age key behind `op_reference`, from its "Public Key" field if the
+/// item has one, otherwise derived from the private key
+pub fn public_key_for_reference(op_reference: &str) -> Result<String, String> {
+    OnePassword::cli().public_key_for_reference(op_reference)
+}
+
+fn read_config() -> Result<SopsConfig, String> {
+    read_or_create_config().map_err(|e| format!("Failed to read SOPS config: {}", e))
+}
+
+/// The 1Password reference of the creation rule matching the path
+fn rule_reference(config: &SopsConfig, path: &Path) -> Result<Option<String>, String> {
+    let rule = find_creation_rule(config, path)?.map(|(_, rule)| rule);
+    Ok(config.onepassword_reference(rule))
+}
+
+fn no_reference_error() -> String {
+    "No 1Password reference found in .sops.yaml. Run 'opsops init' to configure.".to_string()
+}
+
+/// Reads an Age key from a 1Password reference (`op://<vault>/<item>/<field>`), or from the
+/// agent if it holds the key already
+pub fn read_age_key(op_reference: &str) -> Result<SecretString, String> {
+    OnePassword::cli().read_age_key(op_reference)
+}
+
+/// The 1Password CLI, with the agent caching keys in front of it
+struct OnePassword {
+    program: OsString,
+    agent: bool,
+}
+
+impl OnePassword {
+    /// The `op` binary on PATH, through the agent
+    fn cli() -> Self {
+        OnePassword {
+            program: OsString::from("op"),
+            agent: true,
+        }
+    }
+
+    fn find_age_key(
+        &self,
+        references: &[String],
+        recipients: &[String],
+    ) -> Result<SecretString, String> {
+        if references.is_empty() {
+            return Err(no_reference_error());
+        }
+
+        let mut without_public_key = Vec::new();
+        for reference in references {
+            match self.read_public_key(reference) {
+                Ok(public_key) if recipients.contains(&public_key) => {
+                    return self.read_age_key(reference);
+                }
+                Ok(_) => {}
+                Err(_) => without_public_key.push(reference),
+            }
+        }
+
+        // Items without a public key field have to be fetched to find out
+        for reference in without_public_key {
+            if let Ok(key) = self.read_age_key(reference)
+                && age_identity(&key)
+                    .is_ok_and(|identity| recipients.contains(&identity.to_public().to_string()))
+            {
+                return Ok(key);
+            }
+        }
+
+        Err(format!(
+            "none of your keys can decrypt this file; recipients are {}",
+            recipients.join(", ")
+        ))
+    }
+
+    fn public_key_for_reference(&self, op_reference: &str) -> Result<String, String> {
+        if let Ok(public_key) = self.read_public_key(op_reference) {
+            return Ok(public_key);
+        }
+
+        let key = self.read_age_key(op_reference)?;
+        age_identity(&key).map(|identity| identity.to_public().to_string())
+    }
+
+    /// Reads the public key stored next to the private key referenced by `op_reference`
+    fn read_public_key(&self, op_reference: &str) -> Result<String, String> {
+        if let Some(public_key) = self
+            .agent
+            .then(|| cached_public_key(op_reference))
+            .flatten()
+        {
+            return Ok(public_key);
+        }
+
+        let (item, _field) = op_reference
+            .rsplit_once('/')
+            .ok_or_else(|| format!("Invalid 1Password reference: {}", op_reference))?;
+
+        let output = Command::new(&self.program)
+            .arg("read")
+            .arg(format!("{}/{}", item, PUBLIC_KEY_FIELD))
+            .output()
+            .map_err(|e| format!("Failed to execute 1Password CLI: {}", e))?;
+
+        let public_key = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() || !public_key.starts_with("age1") {
+            return Err(format!("No public key stored next to {}", op_reference));
+        }
+        Ok(public_key)
+    }
+
+    fn read_age_key(&self, op_reference: &str) -> Result<SecretString, String> {
+        if let Some(key) = self.agent.then(|| cached_key(op_reference)).flatten() {
+            eprintln!(
+                "{} {}",
+                "🔑 Using Age key from the agent for".dimmed(),
+                op_reference.dimmed()
+            );
+            return Ok(key);
+        }
+
+        // Status goes to stderr so stdout stays clean for piped plaintext
+        eprintln!(
+            "{} {}",
+            "🔑 Retrieving Age key from".dimmed(),
+            op_reference.dimmed()
         );
+
+        // Run the op command to get the key
+        // Format: op://<vault>/<item>/<field>
+        let output = Command::new(&self.program)
+            .arg("read")
+            .arg(op_reference)
+            .output()
+            .map_err(|e| format!("Failed to execute 1Password CLI: {}", e))?;
+
+        if !output.status.success() {
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("1Password CLI returned an error: {}", error));
+        }
+
+        // The output is the key, wipe it once it is copied into a secret
+        let stdout = Zeroizing::new(output.stdout);
+        let key = std::str::from_utf8(&stdout)
+            .map(str::trim)
+            .unwrap_or_default();
+
+        // Validate that we got a proper Age key
+        if !key.starts_with("AGE-SECRET-KEY-") {
+            return Err(
+                "Retrieved value is not a valid Age key. It should start with 'AGE-SECRET-KEY-'."
+                    .to_string(),
+            );
+        }
+
+        let key = secret_key(key.to_string());
+        if self.agent {
+            cache_key(op_reference, &key);
+        }
+        Ok(key)
     }
+}
+
+/// Retrieves the Age key matching the recipients of a file and parses it into an age identity
+pub fn get_age_identity_for_recipients(
+    path: &Path,
+    recipients: &[String],
+) -> Result<Identity, String> {
+    let key = get_age_key_for_recipients(path, recipients)?;
+    age_identity(&key)
+}
+
+/// Parses a key into an age identity
+pub fn age_identity(key: &SecretString) -> Result<Identity, String> {
+    Identity::from_str(key.expose_secret()).map_err(|e| format!("Invalid Age key: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
 
-    // Extract the 1Password reference
-    let op_reference = config.onepassworditem;
-    println!(
-        "{} {}",
-        "🔑 Retrieving Age key from".dimmed(),
-        op_reference.dimmed()
-    );
+    const REFERENCE: &str = "op://Private/key/Private Key";
+    /// An item without a "Public Key" field, holding a key for another recipient
+    const BARE_REFERENCE: &str = "op://Private/bare/Private Key";
 
-    // Run the op command to get the key
-    // Format: op://<vault>/<item>/<field>
-    let output = Command::new("op")
-        .arg("read")
-        .arg(op_reference)
-        .output()
-        .map_err(|e| format!("Failed to execute 1Password CLI: {}", e))?;
+    /// A fake `op` serving `key` under REFERENCE and `other` under BARE_REFERENCE, which has no
+    /// public key field. Anything else reads as a value that isn't a key.
+    fn fake_op(dir: &Path, key: &Identity, other: &Identity) -> OnePassword {
+        let program = dir.join("op");
+        fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\ncase \"$2\" in\n  */key/'Public Key') echo {} ;;\n  */key/*) echo {} ;;\n  */bare/'Public Key') exit 1 ;;\n  */bare/*) echo {} ;;\n  *) echo not-a-key-hunter2 ;;\nesac\n",
+                key.to_public(),
+                key.to_string().expose_secret(),
+                other.to_string().expose_secret()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
 
-    if !output.status.success() {
-        let error = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("1Password CLI returned an error: {}", error));
+        OnePassword {
+            program: program.into_os_string(),
+            agent: false,
+        }
     }
 
-    // Get the output as a string
-    let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    /// Keys come back as secrets that don't show in debug output, and keys that turn out not
+    /// to match are dropped on the way
+    #[test]
+    fn keys_only_leave_op_key_as_secrets() {
+        let identity = Identity::generate();
+        let other = Identity::generate();
+        let key = identity.to_string().expose_secret().to_string();
+        let other_key = other.to_string().expose_secret().to_string();
+        let public_key = identity.to_public().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let op = fake_op(dir.path(), &identity, &other);
 
-    // Validate that we got a proper Age key
-    if !key.starts_with("AGE-SECRET-KEY-") {
-        return Err(
-            "Retrieved value is not a valid Age key. It should start with 'AGE-SECRET-KEY-'."
-                .to_string(),
+        let read: SecretString = op.read_age_key(REFERENCE).unwrap();
+        assert_eq!(read.expose_secret(), key);
+        assert!(!format!("{:?}", read).contains(&key));
+        drop(read);
+
+        // The bare item is read and parsed to find out whom it is for, then discarded
+        let references = [BARE_REFERENCE.to_string(), REFERENCE.to_string()];
+        let found: SecretString = op
+            .find_age_key(&references, std::slice::from_ref(&public_key))
+            .unwrap();
+        assert!(!format!("{:?}", found).contains(&key));
+        assert_eq!(
+            age_identity(&found).unwrap().to_public().to_string(),
+            public_key
+        );
+        drop(found);
+
+        let error = op
+            .find_age_key(
+                &[BARE_REFERENCE.to_string()],
+                std::slice::from_ref(&public_key),
+            )
+            .unwrap_err();
+        assert!(!error.contains(&other_key), "{}", error);
+        assert_eq!(
+            op.public_key_for_reference(BARE_REFERENCE).unwrap(),
+            other.to_public().to_string()
         );
     }
 
-    Ok(key)
+    /// Reads keys through a fake `op` and checks that errors never show what `op` printed
+    #[test]
+    fn read_keys_are_never_printed_or_returned_in_the_clear() {
+        let identity = Identity::generate();
+        let key = identity.to_string().expose_secret().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let op = fake_op(dir.path(), &identity, &Identity::generate());
+
+        let error = op.read_age_key("op://Vault/key2/Other").unwrap_err();
+        assert!(!error.contains("hunter2"), "{}", error);
+        let error = op
+            .find_age_key(&[REFERENCE.to_string()], &["age1other".to_string()])
+            .unwrap_err();
+        assert!(!error.contains(&key), "{}", error);
+    }
 }
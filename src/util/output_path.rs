@@ -0,0 +1,131 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Returns true if the file name carries one of the encrypted markers (`foo.enc.yaml`, `foo.enc`)
+pub fn has_encrypted_marker(path: &Path, markers: &[String]) -> bool {
+    marker_position(path, markers).is_some()
+}
+
+/// The name an encrypted copy of a plaintext file gets: `foo.yaml` -> `foo.enc.yaml`
+pub fn encrypted_path(path: &Path, markers: &[String]) -> Option<PathBuf> {
+    let marker = markers.first()?;
+    let name = path.file_name()?.to_str()?;
+
+    let encrypted_name = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}.{}.{}", stem, marker, extension)
+        }
+        _ => format!("{}.{}", name, marker),
+    };
+    Some(path.with_file_name(encrypted_name))
+}
+
+/// The name the plaintext of an encrypted file gets: `foo.enc.yaml` -> `foo.yaml`, `foo.enc` -> `foo`
+pub fn decrypted_path(path: &Path, markers: &[String]) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let index = marker_position(path, markers)?;
+
+    let parts: Vec<&str> = name.split('.').collect();
+    let plaintext_name = parts
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != index)
+        .map(|(_, part)| *part)
+        .collect::<Vec<_>>()
+        .join(".");
+    if plaintext_name.is_empty() {
+        return None;
+    }
+    Some(path.with_file_name(plaintext_name))
+}
+
+/// Index of the marker among the dot separated parts of the file name.
+/// The marker must be the last part or directly precede the extension.
+fn marker_position(path: &Path, markers: &[String]) -> Option<usize> {
+    let name = path.file_name()?.to_str()?;
+    let parts: Vec<&str> = name.split('.').collect();
+    if parts.len() < 2 {
+        return None;
+    }
+
+    let is_marker = |i: usize| i > 0 && markers.iter().any(|m| m == parts[i]);
+    let last = parts.len() - 1;
+    if is_marker(last) {
+        Some(last)
+    } else if last >= 2 && is_marker(last - 1) {
+        Some(last - 1)
+    } else {
+        None
+    }
+}
+
+/// Refuses to overwrite an existing file unless forced
+pub fn check_overwrite(output: &Path, force: bool) -> Result<(), String> {
+    if output.exists() && !force {
+        return Err(format!(
+            "{} already exists. Use --force to overwrite it.",
+            output.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Replace an existing file in one step, keeping its permissions, so an interruption never
+/// leaves it half written
+pub fn replace_file(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let directory = path.parent().unwrap_or(Path::new("."));
+    let mut temp = tempfile::NamedTempFile::new_in(directory)
+        .map_err(|e| format!("Failed to create temporary file: {}", e))?;
+    temp.write_all(contents)
+        .and_then(|_| fs::metadata(path))
+        .and_then(|m| fs::set_permissions(temp.path(), m.permissions()))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    temp.persist(path)
+        .map_err(|e| format!("Failed to replace {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+/// Write a new file only its owner can read, in one step like `replace_file`. Fails if the
+/// file exists.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let directory = path.parent().unwrap_or(Path::new("."));
+    // Temporary files are created with mode 0600
+    let mut temp = tempfile::NamedTempFile::new_in(directory)
+        .map_err(|e| format!("Failed to create temporary file: {}", e))?;
+    temp.write_all(contents)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    temp.persist_noclobber(path)
+        .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn new_plaintext_is_private_and_never_clobbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.yaml");
+        write_private_file(&path, b"a: 1\n").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        assert!(write_private_file(&path, b"a: 2\n").is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"a: 1\n");
+    }
+
+    #[test]
+    fn replacing_keeps_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.yaml");
+        fs::write(&path, b"old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        replace_file(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+}
@@ -0,0 +1,113 @@
+use std::fs::{self, DirBuilder, Permissions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// The directory opsops' sockets live in by default, which only the user may enter
+pub fn socket_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("opsops"),
+        None => std::env::temp_dir().join(format!("opsops-{}", current_uid())),
+    }
+}
+
+pub fn current_uid() -> u32 {
+    // SAFETY: getuid has no preconditions and always succeeds
+    unsafe { libc::getuid() }
+}
+
+/// Returns true if the socket belongs to the user, so it is safe to hand it keys
+pub fn owned_by_user(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.uid() == current_uid())
+}
+
+/// Bind a socket only the user can connect to, replacing one left by a process that didn't
+/// shut down cleanly. `what` names the server for the error if one is running already.
+pub fn bind_private_socket(path: &Path, what: &str) -> Result<UnixListener, String> {
+    if path.starts_with(socket_dir()) {
+        private_dir(&socket_dir())?;
+    }
+
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!("{} is already running on {}", what, path.display()));
+        }
+        fs::remove_file(path)
+            .map_err(|e| format!("Failed to remove stale socket {}: {}", path.display(), e))?;
+    }
+
+    // Nobody else may connect between bind and chmod
+    // SAFETY: umask has no preconditions. It is process wide, so files other threads create
+    // in the meantime only end up more private.
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    // SAFETY: as above, this restores the previous mask
+    unsafe { libc::umask(umask) };
+    let listener =
+        listener.map_err(|e| format!("Failed to listen on {}: {}", path.display(), e))?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))
+        .map_err(|e| format!("Failed to restrict {}: {}", path.display(), e))?;
+    Ok(listener)
+}
+
+/// Create the socket directory, or check that an existing one is the user's own and private
+fn private_dir(dir: &Path) -> Result<(), String> {
+    if !dir.exists() {
+        return DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e));
+    }
+
+    let metadata =
+        fs::metadata(dir).map_err(|e| format!("Failed to inspect {}: {}", dir.display(), e))?;
+    if metadata.uid() != current_uid() || metadata.mode() & 0o077 != 0 {
+        return Err(format!(
+            "{} has to belong to you and be private (chmod 700)",
+            dir.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Returns true if the process on the other end of the socket runs as the user. The socket is
+/// private already, this also turns away root.
+pub fn peer_is_user(stream: &impl AsRawFd) -> bool {
+    peer_uid(stream) == Some(current_uid())
+}
+
+/// The user id of the process on the other end of the socket
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &impl AsRawFd) -> Option<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: the descriptor is open for as long as `stream` is borrowed, and getsockopt writes
+    // at most `len` bytes, the size of `cred`, which outlives the call
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (result == 0).then_some(cred.uid)
+}
+
+/// The user id of the process on the other end of the socket
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &impl AsRawFd) -> Option<u32> {
+    let mut uid = 0;
+    let mut gid = 0;
+    // SAFETY: the descriptor is open for as long as `stream` is borrowed, and getpeereid only
+    // writes the two ids, which live on this stack frame
+    let result = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    (result == 0).then_some(uid)
+}
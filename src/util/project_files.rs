@@ -0,0 +1,145 @@
+use crate::util::find_project_root::find_project_root;
+use crate::util::output_path::encrypted_path;
+use crate::util::sops_config::find_secret_rule;
+use crate::util::sops_document::is_encrypted_file;
+use crate::util::sops_structs::SopsConfig;
+use git2::Repository;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A file in the project that matches the `path_regex` of a creation rule
+pub struct SecretFile {
+    pub path: PathBuf,
+    pub encrypted: bool,
+}
+
+/// The files of a project, seen the way git sees them: ignored files and directories are left out
+pub struct ProjectFiles {
+    root: PathBuf,
+    repo: Option<Repository>,
+}
+
+impl ProjectFiles {
+    /// Open the project the current directory belongs to
+    pub fn discover() -> Result<Self, String> {
+        let root = find_project_root()
+            .ok_or_else(|| "Could not find the project root".to_string())?
+            .canonicalize()
+            .map_err(|e| format!("Failed to resolve the project root: {}", e))?;
+
+        Ok(Self::open(root))
+    }
+
+    /// Open the project at `root`, which is not required to be a git repository
+    pub fn open(root: PathBuf) -> Self {
+        let repo = Repository::open(&root).ok();
+        ProjectFiles { root, repo }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where opsops keeps a file of its own about the project: inside `.git`, so `git add`
+    /// never picks it up, or hidden in the project root outside of a repository
+    pub fn state_path(&self, name: &str) -> PathBuf {
+        match &self.repo {
+            Some(repo) => repo.path().join(name),
+            None => self.root.join(format!(".{}", name)),
+        }
+    }
+
+    /// Returns true if git ignores the path. Outside of a git repository nothing is ignored.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let Some(repo) = &self.repo else {
+            return false;
+        };
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        repo.is_path_ignored(relative).unwrap_or(false)
+    }
+
+    /// Every file below the project root, skipping `.git`, directories git ignores and the
+    /// `.sops.yaml` config itself, which is never a secret
+    pub fn files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = WalkDir::new(&self.root)
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.depth() == 0
+                    || !entry.file_type().is_dir()
+                    || (entry.file_name() != ".git" && !self.is_ignored(entry.path()))
+            })
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file() && entry.file_name() != ".sops.yaml")
+            .map(|entry| entry.into_path())
+            .collect();
+
+        files.sort();
+        files
+    }
+
+    /// The path relative to the project root, for display
+    pub fn display_path(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    }
+
+    /// Every file that falls under a creation rule with a `path_regex`, encrypted or not, see
+    /// `find_secret_rule`. Plaintext that git ignores is still included, as long as its
+    /// encrypted copy isn't ignored.
+    pub fn secret_files(&self, config: &SopsConfig) -> Vec<SecretFile> {
+        let markers = config.encrypted_markers();
+
+        self.files()
+            .into_iter()
+            .filter(|path| find_secret_rule(config, path).is_some())
+            .filter_map(|path| {
+                let encrypted = is_encrypted_file(&path);
+                let ignored = self.is_ignored(&path)
+                    && (encrypted
+                        || encrypted_path(&path, &markers).is_none_or(|e| self.is_ignored(&e)));
+                (!ignored).then_some(SecretFile { path, encrypted })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plaintext_is_a_secret_when_its_encrypted_name_matches_a_rule() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("foo.yaml"), "password: hunter2\n").unwrap();
+        std::fs::write(dir.path().join("bar.json"), "{}\n").unwrap();
+        let config: SopsConfig = serde_yaml::from_str(
+            "creation_rules:\n  - path_regex: \\.enc\\.yaml$\n    age: age1example\n",
+        )
+        .unwrap();
+
+        let project = ProjectFiles::open(dir.path().to_path_buf());
+        let secrets: Vec<PathBuf> = project
+            .secret_files(&config)
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+
+        assert_eq!(secrets, vec![dir.path().join("foo.yaml")]);
+    }
+
+    #[test]
+    fn state_stays_out_of_the_work_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = ProjectFiles::open(dir.path().to_path_buf());
+        assert_eq!(project.state_path("state"), dir.path().join(".state"));
+
+        Repository::init(dir.path()).unwrap();
+        let project = ProjectFiles::open(dir.path().to_path_buf());
+        assert_eq!(
+            project.state_path("state"),
+            dir.path().join(".git").join("state")
+        );
+    }
+}
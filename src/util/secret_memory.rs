@@ -0,0 +1,46 @@
+use age::secrecy::{ExposeSecret, SecretString};
+use std::sync::Once;
+
+static CORE_DUMPS_DISABLED: Once = Once::new();
+
+/// Takes a private key read from 1Password or the agent. The key is zeroized when dropped and
+/// kept out of swap, and core dumps are off from the first key on.
+pub fn secret_key(key: String) -> SecretString {
+    disable_core_dumps();
+    let key = SecretString::from(key);
+    lock_memory(key.expose_secret().as_bytes());
+    key
+}
+
+/// Turn off core dumps for the rest of the process, so a crash doesn't write keys to disk
+pub fn disable_core_dumps() {
+    CORE_DUMPS_DISABLED.call_once(|| {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: both calls only read or write the rlimit struct they are given, which lives
+        // on this stack frame for the duration of the call
+        unsafe {
+            if libc::getrlimit(libc::RLIMIT_CORE, &mut limit) == 0 {
+                limit.rlim_cur = 0;
+                libc::setrlimit(libc::RLIMIT_CORE, &limit);
+            }
+        }
+    });
+}
+
+/// Lock the pages holding `bytes` in memory, so they are never swapped out. Best effort, as
+/// the limit on locked memory can be low. The pages stay locked until the process exits.
+pub fn lock_memory(bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    // SAFETY: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = bytes.as_ptr() as usize & !(page_size - 1);
+    let end = bytes.as_ptr() as usize + bytes.len();
+    // SAFETY: the range covers only pages of the live allocation behind `bytes`; mlock doesn't
+    // read or write memory, it only pins the pages, and fails harmlessly over the limit
+    unsafe { libc::mlock(start as *const libc::c_void, end - start) };
+}
@@ -0,0 +1,176 @@
+use aes_gcm::{
+    AeadCore, AesGcm, KeyInit,
+    aead::{Aead, OsRng, Payload, consts::U32},
+    aes::Aes256,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use std::fmt;
+use zeroize::{Zeroize, Zeroizing};
+
+/// SOPS uses AES-256-GCM with a 32 byte nonce instead of the usual 12
+type SopsAesGcm = AesGcm<Aes256, U32>;
+
+const ENC_PREFIX: &str = "ENC[AES256_GCM,";
+const TAG_LEN: usize = 16;
+
+/// A single `ENC[AES256_GCM,data:...,iv:...,tag:...,type:...]` value
+pub struct EncryptedValue {
+    pub data: Vec<u8>,
+    pub iv: Vec<u8>,
+    pub tag: Vec<u8>,
+    pub value_type: String,
+}
+
+/// Generate a fresh random 256 bit data key, zeroized when dropped
+pub fn generate_data_key() -> Zeroizing<Vec<u8>> {
+    let mut key = SopsAesGcm::generate_key(&mut OsRng);
+    let data_key = Zeroizing::new(key.to_vec());
+    key.zeroize();
+    data_key
+}
+
+impl EncryptedValue {
+    /// Encrypt a plaintext with the data key, binding `aad` to the ciphertext
+    pub fn encrypt(
+        plaintext: &[u8],
+        key: &[u8],
+        aad: &str,
+        value_type: &str,
+    ) -> Result<Self, String> {
+        let cipher =
+            SopsAesGcm::new_from_slice(key).map_err(|_| "Invalid data key length".to_string())?;
+        let iv = SopsAesGcm::generate_nonce(&mut OsRng);
+
+        let mut data = cipher
+            .encrypt(
+                &iv,
+                Payload {
+                    msg: plaintext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| "Failed to encrypt value".to_string())?;
+        let tag = data.split_off(data.len() - TAG_LEN);
+
+        Ok(EncryptedValue {
+            data,
+            iv: iv.to_vec(),
+            tag,
+            value_type: value_type.to_string(),
+        })
+    }
+
+    /// Returns true if the string looks like a SOPS encrypted value
+    pub fn is_encrypted(value: &str) -> bool {
+        value.starts_with(ENC_PREFIX)
+    }
+
+    /// Parse the textual SOPS representation of an encrypted value
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let inner = value
+            .strip_prefix(ENC_PREFIX)
+            .and_then(|v| v.strip_suffix(']'))
+            .ok_or_else(|| format!("Not a SOPS encrypted value: {}", value))?;
+
+        let mut data = None;
+        let mut iv = None;
+        let mut tag = None;
+        let mut value_type = None;
+
+        for part in inner.split(',') {
+            let (name, content) = part
+                .split_once(':')
+                .ok_or_else(|| format!("Malformed SOPS value component: {}", part))?;
+            match name {
+                "data" => data = Some(decode(content)?),
+                "iv" => iv = Some(decode(content)?),
+                "tag" => tag = Some(decode(content)?),
+                "type" => value_type = Some(content.to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(EncryptedValue {
+            data: data.ok_or("SOPS value is missing 'data'")?,
+            iv: iv.ok_or("SOPS value is missing 'iv'")?,
+            tag: tag.ok_or("SOPS value is missing 'tag'")?,
+            value_type: value_type.ok_or("SOPS value is missing 'type'")?,
+        })
+    }
+
+    /// Decrypt the value with the data key, authenticating `aad`
+    pub fn decrypt(&self, key: &[u8], aad: &str) -> Result<Vec<u8>, String> {
+        if self.tag.len() != TAG_LEN {
+            return Err("Invalid authentication tag length".to_string());
+        }
+        if self.iv.len() != 32 {
+            return Err("Invalid IV length".to_string());
+        }
+
+        let cipher =
+            SopsAesGcm::new_from_slice(key).map_err(|_| "Invalid data key length".to_string())?;
+
+        // aes-gcm expects the tag appended to the ciphertext
+        let mut ciphertext = self.data.clone();
+        ciphertext.extend_from_slice(&self.tag);
+
+        cipher
+            .decrypt(
+                self.iv.as_slice().into(),
+                Payload {
+                    msg: &ciphertext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| "Failed to decrypt value: authentication failed".to_string())
+    }
+}
+
+impl fmt::Display for EncryptedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}data:{},iv:{},tag:{},type:{}]",
+            ENC_PREFIX,
+            STANDARD.encode(&self.data),
+            STANDARD.encode(&self.iv),
+            STANDARD.encode(&self.tag),
+            self.value_type
+        )
+    }
+}
+
+fn decode(content: &str) -> Result<Vec<u8>, String> {
+    STANDARD
+        .decode(content)
+        .map_err(|e| format!("Invalid base64 in SOPS value: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_its_text_form() {
+        let key = generate_data_key();
+        let value = EncryptedValue::encrypt(b"hunter2", &key, "db:password:", "str").unwrap();
+        let parsed = EncryptedValue::parse(&value.to_string()).unwrap();
+        assert_eq!(parsed.value_type, "str");
+        assert_eq!(parsed.iv.len(), 32);
+        assert_eq!(parsed.decrypt(&key, "db:password:").unwrap(), b"hunter2");
+    }
+
+    #[test]
+    fn authenticates_the_path_and_key() {
+        let key = generate_data_key();
+        let value = EncryptedValue::encrypt(b"hunter2", &key, "db:password:", "str").unwrap();
+        assert!(value.decrypt(&key, "db:user:").is_err());
+        assert!(value.decrypt(&generate_data_key(), "db:password:").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        assert!(EncryptedValue::parse("ENC[AES256_GCM,data:AA==,iv:AA==,type:str]").is_err());
+        assert!(EncryptedValue::parse("plain").is_err());
+    }
+}
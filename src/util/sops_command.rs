@@ -1,19 +1,45 @@
+use crate::util::find_project_root::find_project_root;
 use crate::util::op_key::get_age_key_from_1password;
-use std::process::{Child, Command, Stdio};
+use crate::util::sops_config::read_or_create_config;
+use crate::util::sops_structs::SopsConfig;
+use age::secrecy::{ExposeSecret, SecretString};
+use colored::Colorize;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tempfile::NamedTempFile;
+
+/// Holds the user's editor while sops runs `opsops sops-editor`, which starts it without the key
+pub const EDITOR_ENV: &str = "OPSOPS_SOPS_EDITOR";
 
 /// A helper type for executing SOPS commands with the Age key from 1Password
 pub struct SopsCommandBuilder {
     command: Command,
-    has_age_key: bool,
+    /// The private key sops reads through `SOPS_AGE_KEY_FILE=/dev/fd/N`, closed once sops runs
+    age_key_fd: Option<OwnedFd>,
+    /// .sops.yaml with recipient names replaced by keys, removed when the command is done
+    _expanded_config: Option<NamedTempFile>,
 }
 
 impl SopsCommandBuilder {
-    /// Create a new SopsCommandBuilder initialized with the sops binary
+    /// Create a new SopsCommandBuilder initialized with the sops binary.
+    /// If creation rules list recipients by name, sops gets a copy of .sops.yaml with the
+    /// names replaced by their keys through `--config`.
     pub fn new() -> Self {
-        let command = Command::new("sops");
+        let mut command = Command::new("sops");
+        let expanded_config = expanded_config_file();
+        if let Some(file) = &expanded_config {
+            command.arg("--config").arg(file.path());
+        }
+
         SopsCommandBuilder {
             command,
-            has_age_key: false,
+            age_key_fd: None,
+            _expanded_config: expanded_config,
         }
     }
 
@@ -33,28 +59,54 @@ impl SopsCommandBuilder {
         self
     }
 
-    /// Set the working directory for the command
-    pub fn current_dir<P: AsRef<std::path::Path>>(mut self, dir: P) -> Self {
-        self.command.current_dir(dir);
-        self
+    /// Configure with the Age key from 1Password for the file sops works on
+    pub fn with_age_key<P: AsRef<std::path::Path>>(self, path: P) -> Result<Self, String> {
+        // Retrieve the Age key from 1Password
+        let age_key = get_age_key_from_1password(path.as_ref())?;
+        self.try_with_key(&age_key)
     }
 
-    /// Configure with Age key from 1Password (if it exists)
-    pub fn with_age_key(mut self) -> Result<Self, String> {
-        // Retrieve the Age key from 1Password
-        let age_key = get_age_key_from_1password()?;
-        self.command.env("SOPS_AGE_KEY", age_key);
-        self.has_age_key = true;
-        Ok(self)
+    /// Configure with an Age key that was already retrieved
+    pub fn with_key(self, age_key: &SecretString) -> Self {
+        match self.try_with_key(age_key) {
+            Ok(builder) => builder,
+            Err(e) => {
+                eprintln!("{} {}", "❌ Failed to hand the Age key to sops:".red(), e);
+                std::process::exit(1);
+            }
+        }
     }
 
-    /// Try to set the Age key, but don't fail if it's not available
-    pub fn with_optional_age_key(mut self) -> Self {
-        if let Ok(age_key) = get_age_key_from_1password() {
-            self.command.env("SOPS_AGE_KEY", age_key);
-            self.has_age_key = true;
+    /// Hand the key to sops through an inherited file descriptor instead of `SOPS_AGE_KEY`,
+    /// which other processes of the user can read from `/proc/<pid>/environ` and which sops
+    /// passes on to the editor. The editor runs through `opsops sops-editor`, without the key.
+    fn try_with_key(mut self, age_key: &SecretString) -> Result<Self, String> {
+        let fd = age_key_fd(age_key).map_err(|e| e.to_string())?;
+        let raw_fd = fd.as_raw_fd();
+
+        let editor_shim = editor_shim().map_err(|e| e.to_string())?;
+        if let Some(editor) = std::env::var_os("EDITOR") {
+            self.command.env(EDITOR_ENV, editor);
         }
-        self
+        self.command
+            .env_remove("SOPS_AGE_KEY")
+            .env("SOPS_AGE_KEY_FILE", format!("/dev/fd/{}", raw_fd))
+            .env("EDITOR", editor_shim);
+
+        // The descriptor is opened close-on-exec, so only sops inherits it
+        // SAFETY: the closure runs in the forked child before exec, where only async-signal-safe
+        // calls are allowed. It makes one fcntl call and reads errno, neither allocates nor
+        // takes locks. `fd` stays open in `self.age_key_fd` until the command has run.
+        unsafe {
+            self.command.pre_exec(move || {
+                if libc::fcntl(raw_fd, libc::F_SETFD, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        self.age_key_fd = Some(fd);
+        Ok(self)
     }
 
     /// Run the command and wait for it to finish
@@ -62,36 +114,132 @@ impl SopsCommandBuilder {
         self.command.status()
     }
 
-    /// Spawn the command and return the Child process handle
-    pub fn spawn(mut self) -> std::io::Result<Child> {
-        self.command.spawn()
-    }
-
     /// Run the command and capture its output
     pub fn output(mut self) -> std::io::Result<std::process::Output> {
         self.command.output()
     }
 
-    /// Check if the Age key was successfully set
-    pub fn has_age_key(&self) -> bool {
-        self.has_age_key
+    /// Set stderr for the command
+    pub fn stderr(mut self, cfg: Stdio) -> Self {
+        self.command.stderr(cfg);
+        self
     }
+}
 
-    /// Set stdin for the command
-    pub fn stdin(mut self, cfg: Stdio) -> Self {
-        self.command.stdin(cfg);
-        self
+/// The key in a sealed in-memory file. sops opens `/dev/fd/N` anew for every age key it tries,
+/// and on Linux each open reads the file from the start.
+#[cfg(target_os = "linux")]
+fn age_key_fd(age_key: &SecretString) -> std::io::Result<OwnedFd> {
+    // SAFETY: the name is a valid nul-terminated string and the flags are plain bits
+    let fd = unsafe {
+        libc::memfd_create(
+            c"opsops-age-key".as_ptr(),
+            libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+        )
+    };
+    if fd == -1 {
+        return Err(std::io::Error::last_os_error());
     }
+    // SAFETY: memfd_create just returned this descriptor, nothing else owns it
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(age_key.expose_secret().as_bytes())?;
 
-    /// Set stdout for the command
-    pub fn stdout(mut self, cfg: Stdio) -> Self {
-        self.command.stdout(cfg);
-        self
+    let seals = libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+    // SAFETY: the descriptor is owned by `file` and stays open, F_ADD_SEALS takes no pointers
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) } == -1 {
+        return Err(std::io::Error::last_os_error());
     }
+    Ok(file.into())
+}
 
-    /// Set stderr for the command
-    pub fn stderr(mut self, cfg: Stdio) -> Self {
-        self.command.stderr(cfg);
-        self
+/// The key in a pipe. Other systems share the offset between opens of `/dev/fd/N`, so sops
+/// can read the key once per command.
+#[cfg(not(target_os = "linux"))]
+fn age_key_fd(age_key: &SecretString) -> std::io::Result<OwnedFd> {
+    let mut fds = [0; 2];
+    // SAFETY: pipe writes two descriptors into `fds`, which has room for exactly two
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: pipe just returned both descriptors, each is owned exactly once from here on
+    let read = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+    // SAFETY: as above
+    let mut write = unsafe { File::from_raw_fd(fds[1]) };
+    for fd in fds {
+        // SAFETY: both descriptors are open, owned by `read` and `write`; F_SETFD takes no
+        // pointers
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    // The key is far smaller than the pipe buffer, so this doesn't block
+    write.write_all(age_key.expose_secret().as_bytes())?;
+    Ok(read)
+}
+
+/// `EDITOR` for sops: this binary's `sops-editor` command, quoted for sops' shell-like split
+fn editor_shim() -> std::io::Result<OsString> {
+    let exe = std::env::current_exe()?;
+    let mut shim = OsString::from("'");
+    shim.push(exe.as_os_str().to_string_lossy().replace('\'', "'\\''"));
+    shim.push(OsStr::new("' sops-editor"));
+    Ok(shim)
+}
+
+/// Writes the expanded config next to .sops.yaml when it lists recipients by name. It has to
+/// live in the project root, sops matches `path_regex` relative to the config file.
+fn expanded_config_file() -> Option<NamedTempFile> {
+    let config = read_or_create_config()
+        .ok()
+        .filter(|c| c.uses_recipient_names())?;
+    let root = find_project_root()?;
+
+    match write_expanded_config(&config, &root) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            eprintln!(
+                "{} {}",
+                "⚠️  Failed to write .sops.yaml with recipient names resolved:".yellow(),
+                e
+            );
+            None
+        }
+    }
+}
+
+fn write_expanded_config(config: &SopsConfig, root: &Path) -> Result<NamedTempFile, String> {
+    tempfile::Builder::new()
+        .prefix(".sops.opsops-")
+        .suffix(".yaml")
+        .tempfile_in(root)
+        .map_err(|e| e.to_string())
+        .and_then(|file| {
+            serde_yaml::to_writer(&file, &config.expanded())
+                .map(|_| file)
+                .map_err(|e| e.to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sops_reads_a_config_with_the_names_resolved() {
+        let root = tempfile::tempdir().unwrap();
+        let key = "age13a4en57fuq3skac33qumj524ga5v4gllr7qewfw257e3yreehdgq8k754w";
+        let config: SopsConfig = serde_yaml::from_str(&format!(
+            "creation_rules:\n  - age: alice\nrecipients:\n  alice: {}\n",
+            key
+        ))
+        .unwrap();
+
+        let file = write_expanded_config(&config, root.path()).unwrap();
+        assert_eq!(file.path().parent(), Some(root.path()));
+        let written: SopsConfig =
+            serde_yaml::from_str(&std::fs::read_to_string(file.path()).unwrap()).unwrap();
+        assert_eq!(written.creation_rules[0].age_recipients(), vec![key]);
+
+        let path = file.path().to_path_buf();
+        drop(file);
+        assert!(!path.exists());
     }
 }
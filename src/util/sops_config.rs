@@ -1,12 +1,15 @@
 use std::{
-    fs::File,
-    io::{Read, Write},
+    fs::{self, File},
+    io::Read,
+    path::Path,
 };
 
+use super::output_path::{encrypted_path, has_encrypted_marker, replace_file};
 use super::sops_structs::{CreationRule, SopsConfig};
+use super::yaml_edit::update_yaml;
 use crate::util;
 use colored::Colorize;
-use serde::Deserialize;
+use regex::Regex;
 use serde_yaml::{from_str, to_string};
 
 pub fn get_sops_config() -> Option<File> {
@@ -46,65 +49,138 @@ pub fn read_or_create_config() -> Result<SopsConfig, String> {
                 return Err(format!("Failed to read config file: {}", e));
             }
 
-            // Try parsing as-is first
-            match from_str::<SopsConfig>(&contents) {
-                Ok(config) => Ok(config),
-                Err(e) => {
-                    // If parsing fails due to missing onepassworditem field, parse manually
-                    if e.to_string().contains("missing field `onepassworditem`") {
-                        // Use a custom approach to parse the config without the onepassworditem field
-                        #[derive(Deserialize)]
-                        struct PartialConfig {
-                            #[serde(default)]
-                            creation_rules: Vec<CreationRule>,
-                        }
-
-                        // Try to parse the partial config
-                        match from_str::<PartialConfig>(&contents) {
-                            Ok(partial) => {
-                                // Create a complete config with the parsed rules and empty onepassworditem
-                                Ok(SopsConfig {
-                                    creation_rules: partial.creation_rules,
-                                    onepassworditem: String::new(),
-                                })
-                            }
-                            Err(e) => Err(format!("Failed to parse partial YAML config: {}", e)),
-                        }
-                    } else {
-                        Err(format!("Failed to parse YAML: {}", e))
-                    }
-                }
-            }
+            from_str::<SopsConfig>(&contents).map_err(|e| {
+                format!(
+                    "Failed to parse .sops.yaml: {} (run `opsops config validate` for details)",
+                    e
+                )
+            })
         }
         None => {
             // Create a new config with default values
-            Ok(SopsConfig {
-                creation_rules: Vec::new(),
-                onepassworditem: String::new(),
-            })
+            Ok(SopsConfig::default())
         }
     }
 }
 
+/// Writes the config to .sops.yaml. An existing file is only patched where the config changed,
+/// so comments, ordering and settings opsops doesn't know about survive.
 pub fn write_config(config: &SopsConfig) -> Result<(), String> {
-    if let Some(project_root) = util::find_project_root::find_project_root() {
-        let config_path = project_root.join(".sops.yaml");
-        let yaml = match to_string(config) {
-            Ok(y) => y,
-            Err(e) => return Err(format!("Failed to serialize config: {}", e)),
-        };
+    let Some(project_root) = util::find_project_root::find_project_root() else {
+        return Err("Could not determine project root".to_string());
+    };
+    let config_path = project_root.join(".sops.yaml");
 
-        let mut file = match File::create(config_path) {
-            Ok(f) => f,
-            Err(e) => return Err(format!("Failed to create config file: {}", e)),
-        };
+    // An existing file is patched, so its comments and formatting are never lost
+    let yaml = match fs::read_to_string(&config_path) {
+        Ok(original) => update_config_text(&original, config).map_err(|e| {
+            format!(
+                "Failed to update .sops.yaml without losing its formatting, edit it by hand: {}",
+                e
+            )
+        })?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => serialize_config(config)?,
+        Err(e) => return Err(format!("Failed to read config file: {}", e)),
+    };
+
+    if config_path.exists() {
+        return replace_file(&config_path, yaml.as_bytes());
+    }
+    fs::write(&config_path, yaml).map_err(|e| format!("Failed to write to config file: {}", e))
+}
+
+fn serialize_config(config: &SopsConfig) -> Result<String, String> {
+    to_string(config).map_err(|e| format!("Failed to serialize config: {}", e))
+}
+
+/// Patch the text of .sops.yaml with the changes between what it holds and `config`
+fn update_config_text(original: &str, config: &SopsConfig) -> Result<String, String> {
+    let to_value = |config: &SopsConfig| {
+        serde_yaml::to_value(config).map_err(|e| format!("Failed to serialize config: {}", e))
+    };
+    let current: SopsConfig =
+        from_str(original).map_err(|e| format!("Failed to parse YAML: {}", e))?;
+    let wanted = to_value(config)?;
+    let updated = update_yaml(original, &to_value(&current)?, &wanted)?;
+
+    // The patched text has to read back as exactly the config that was asked for
+    let written = from_str::<SopsConfig>(&updated)
+        .map_err(|e| e.to_string())
+        .and_then(|c| to_value(&c));
+    if written.as_ref() != Ok(&wanted) {
+        return Err("the patched file didn't match the new config".to_string());
+    }
+    Ok(updated)
+}
+
+/// Finds the creation rule that applies to a file, evaluated like sops does:
+/// rules are tried in order against the path relative to the project root and the first match wins.
+/// Returns the index of the rule along with the rule itself.
+pub fn find_creation_rule<'a>(
+    config: &'a SopsConfig,
+    path: &Path,
+) -> Result<Option<(usize, &'a CreationRule)>, String> {
+    let relative_path = path_relative_to_project_root(path);
 
-        if let Err(e) = file.write_all(yaml.as_bytes()) {
-            return Err(format!("Failed to write to config file: {}", e));
+    for (i, rule) in config.creation_rules.iter().enumerate() {
+        let Some(pattern) = &rule.path_regex else {
+            return Ok(Some((i, rule)));
+        };
+        if pattern.is_empty() {
+            return Ok(Some((i, rule)));
         }
 
-        Ok(())
-    } else {
-        Err("Could not determine project root".to_string())
+        let regex = Regex::new(pattern)
+            .map_err(|e| format!("Invalid path_regex '{}' in rule #{}: {}", pattern, i + 1, e))?;
+        if regex.is_match(&relative_path) {
+            return Ok(Some((i, rule)));
+        }
     }
+
+    Ok(None)
+}
+
+/// Returns true if a creation rule with a `path_regex` matches the file.
+/// Catch-all rules are ignored here, otherwise `--all` would pick up every file in the project.
+pub fn matches_explicit_rule(config: &SopsConfig, path: &Path) -> bool {
+    explicit_rule(config, path).is_some()
+}
+
+/// The rule a project file falls under as a secret: the one matching the file, or for plaintext
+/// the one matching the name its encrypted copy gets (`foo.yaml` -> `foo.enc.yaml`), which is
+/// the rule `encrypt` uses. Catch-all rules are ignored like in `matches_explicit_rule`.
+pub fn find_secret_rule<'a>(
+    config: &'a SopsConfig,
+    path: &Path,
+) -> Option<(usize, &'a CreationRule)> {
+    let markers = config.encrypted_markers();
+    explicit_rule(config, path).or_else(|| {
+        if has_encrypted_marker(path, &markers) {
+            return None;
+        }
+        explicit_rule(config, &encrypted_path(path, &markers)?)
+    })
+}
+
+fn explicit_rule<'a>(config: &'a SopsConfig, path: &Path) -> Option<(usize, &'a CreationRule)> {
+    find_creation_rule(config, path)
+        .ok()
+        .flatten()
+        .filter(|(_, rule)| rule.path_regex.as_deref().is_some_and(|r| !r.is_empty()))
+}
+
+/// Returns the path as a string relative to the project root, or absolute if it lies outside of
+/// it. sops does the same with the directory of the config file, which is the project root.
+pub fn path_relative_to_project_root(path: &Path) -> String {
+    let absolute = path
+        .canonicalize()
+        .or_else(|_| std::env::current_dir().map(|dir| dir.join(path)))
+        .unwrap_or_else(|_| path.to_path_buf());
+
+    util::find_project_root::find_project_root()
+        .and_then(|root| root.canonicalize().ok())
+        .and_then(|root| absolute.strip_prefix(root).ok().map(Path::to_path_buf))
+        .unwrap_or(absolute)
+        .to_string_lossy()
+        .to_string()
 }
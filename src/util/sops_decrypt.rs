@@ -0,0 +1,45 @@
+use crate::util::op_key::get_age_identity_for_recipients;
+use crate::util::sops_command::SopsCommandBuilder;
+use crate::util::sops_document::{SopsDocument, SopsFormat, emit_plaintext};
+use colored::Colorize;
+use std::path::Path;
+use std::process::Stdio;
+
+/// Decrypts a file into memory with the Age key from 1Password.
+/// YAML, JSON and binary files are decrypted natively, anything else is handed to sops.
+pub fn decrypt_file(path: &Path) -> Result<Vec<u8>, String> {
+    if SopsFormat::from_path(path).is_none() {
+        return decrypt_file_with_sops(path);
+    }
+
+    let document = SopsDocument::load(path)?;
+    if let Some(reason) = document.requires_sops() {
+        eprintln!("{} {}", "ℹ️ Falling back to sops:".blue(), reason);
+        return decrypt_file_with_sops(path);
+    }
+
+    let identity = get_age_identity_for_recipients(path, &document.metadata.recipients())?;
+    let tree = document.decrypt(&identity)?;
+    emit_plaintext(&tree, document.format)
+}
+
+/// Decrypts a file into memory by capturing the output of `sops --decrypt`
+fn decrypt_file_with_sops(path: &Path) -> Result<Vec<u8>, String> {
+    if which::which("sops").is_err() {
+        return Err("'sops' is not installed or not in PATH. Please install it first.".to_string());
+    }
+
+    let output = SopsCommandBuilder::new()
+        .arg("--decrypt")
+        .arg(path)
+        .with_age_key(path)?
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Failed to launch sops: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!("sops exited with {}", output.status));
+    }
+
+    Ok(output.stdout)
+}
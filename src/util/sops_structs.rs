@@ -1,22 +1,606 @@
+use super::sops_document::SopsFormat;
 use serde::{Deserialize, Serialize};
+use serde_yaml::Mapping;
+use std::collections::BTreeMap;
+use std::iter;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct SopsConfig {
     #[serde(default)]
     pub creation_rules: Vec<CreationRule>,
+    #[serde(default)]
     pub onepassworditem: String,
+    /// Named 1Password references that creation rules can refer to (`prod: op://...`)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub onepassworditems: BTreeMap<String, String>,
+    /// Team members by name, creation rules can list these names instead of their keys
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub recipients: BTreeMap<String, TeamMember>,
+    /// Markers that identify encrypted files by name (`foo.enc.yaml`), the first is used for new files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_markers: Option<Vec<String>>,
+    /// Rules for `sops publish`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub destination_rules: Vec<DestinationRule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stores: Option<Stores>,
+    /// Settings neither sops nor opsops know (yet), kept as written
+    #[serde(flatten)]
+    pub other: Mapping,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Markers used when `encrypted_markers` isn't set in .sops.yaml
+const DEFAULT_ENCRYPTED_MARKERS: [&str; 2] = ["enc", "sops"];
+
+impl SopsConfig {
+    /// The configured encrypted file markers, or the defaults
+    pub fn encrypted_markers(&self) -> Vec<String> {
+        match &self.encrypted_markers {
+            Some(markers) if !markers.is_empty() => markers.clone(),
+            _ => DEFAULT_ENCRYPTED_MARKERS
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+        }
+    }
+
+    /// The 1Password reference for files under `rule`: the rule's own `onepassworditem`
+    /// (an `op://` reference or a name from `onepassworditems`), else the project wide one
+    pub fn onepassword_reference(&self, rule: Option<&CreationRule>) -> Option<String> {
+        let reference = rule
+            .and_then(|r| r.onepassworditem.as_deref())
+            .filter(|r| !r.is_empty())
+            .map(|r| self.onepassworditems.get(r).map_or(r, String::as_str))
+            .unwrap_or(&self.onepassworditem);
+
+        (!reference.is_empty()).then(|| reference.to_string())
+    }
+
+    /// Every distinct 1Password reference in the config
+    pub fn onepassword_references(&self) -> Vec<String> {
+        let mut references: Vec<String> = Vec::new();
+        let candidates = iter::once(self.onepassword_reference(None))
+            .chain(self.onepassworditems.values().map(|r| Some(r.clone())))
+            .chain(
+                self.creation_rules
+                    .iter()
+                    .map(|rule| self.onepassword_reference(Some(rule))),
+            )
+            .chain(
+                self.recipients
+                    .values()
+                    .map(|member| member.onepassworditem().map(str::to_string)),
+            );
+
+        for reference in candidates.flatten() {
+            if !reference.is_empty() && !references.contains(&reference) {
+                references.push(reference);
+            }
+        }
+        references
+    }
+
+    /// The public key for an entry of a rule's recipients: a name from `recipients`, or a key
+    pub fn resolve_recipient(&self, entry: &str) -> String {
+        self.recipients
+            .get(entry)
+            .map_or(entry, TeamMember::key)
+            .to_string()
+    }
+
+    /// The public keys a rule encrypts for, with names from `recipients` resolved
+    pub fn rule_recipients(&self, rule: &CreationRule) -> Vec<String> {
+        let mut keys: Vec<String> = Vec::new();
+        for key in rule
+            .age_recipients()
+            .iter()
+            .map(|r| self.resolve_recipient(r))
+        {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+
+    /// The names a public key is known by in `recipients`
+    pub fn recipient_names(&self, key: &str) -> Vec<String> {
+        self.recipients
+            .iter()
+            .filter(|(_, member)| member.key() == key)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// A public key or rule entry for display: the team member's name if it has one
+    pub fn recipient_label(&self, entry: &str) -> String {
+        if self.recipients.contains_key(entry) {
+            return entry.to_string();
+        }
+        self.recipient_names(entry)
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| entry.to_string())
+    }
+
+    /// Returns true if a creation rule lists a recipient by name, which sops can't read
+    pub fn uses_recipient_names(&self) -> bool {
+        self.creation_rules
+            .iter()
+            .flat_map(|rule| rule.age_recipients())
+            .any(|entry| self.recipients.contains_key(&entry))
+    }
+
+    /// A copy of the config with every recipient name replaced by its public key, for sops
+    pub fn expanded(&self) -> SopsConfig {
+        let mut config = self.clone();
+        let rules = config.creation_rules.iter_mut().chain(
+            config
+                .destination_rules
+                .iter_mut()
+                .filter_map(|d| d.recreation_rule.as_mut()),
+        );
+        for rule in rules {
+            rule.map_age_recipients(|entry| self.resolve_recipient(entry));
+        }
+        config.recipients.clear();
+        config
+    }
+
+    /// The indentation `stores` sets for files of `format`
+    pub fn store_indent(&self, format: SopsFormat) -> Option<usize> {
+        let stores = self.stores.as_ref()?;
+        let store = match format {
+            SopsFormat::Yaml => &stores.yaml,
+            SopsFormat::Json => &stores.json,
+            SopsFormat::Binary => &stores.json_binary,
+        };
+        store.as_ref().and_then(|store| store.indent)
+    }
+
+    /// Returns why files of `format` can't be written natively with the `stores` settings
+    pub fn store_requires_sops(&self, format: SopsFormat) -> Option<String> {
+        match self.store_indent(format) {
+            Some(indent) if format == SopsFormat::Yaml && indent != 2 => Some(format!(
+                "stores.yaml.indent is {}, opsops indents YAML by 2",
+                indent
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of the `recipients` team directory: just the public key (`alice: age1...`), or the
+/// key along with the 1Password item holding the private key
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum TeamMember {
+    Key(String),
+    Member {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        onepassworditem: Option<String>,
+    },
+}
+
+impl TeamMember {
+    pub fn key(&self) -> &str {
+        match self {
+            TeamMember::Key(key) | TeamMember::Member { key, .. } => key,
+        }
+    }
+
+    pub fn onepassworditem(&self) -> Option<&str> {
+        match self {
+            TeamMember::Key(_) => None,
+            TeamMember::Member {
+                onepassworditem, ..
+            } => onepassworditem.as_deref(),
+        }
+    }
+}
+
+/// A creation rule, with every setting of the upstream schema
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct CreationRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub path_regex: Option<String>,
-    pub age: Option<String>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub age: Option<KeyList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pgp: Option<KeyList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kms: Option<KeyList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aws_profile: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gcp_kms: Option<KeyList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub azure_keyvault: Option<KeyList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hc_vault_transit_uri: Option<KeyList>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub key_groups: Vec<KeyGroup>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shamir_threshold: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_suffix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unencrypted_suffix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_regex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unencrypted_regex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_comment_regex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unencrypted_comment_regex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac_only_encrypted: Option<bool>,
+    /// 1Password reference (or name from `onepassworditems`) for files matching this rule
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub onepassworditem: Option<String>,
+    /// Keys sops doesn't know (yet), kept as written
+    #[serde(flatten)]
+    pub other: Mapping,
+}
+
+/// The keys of one type in a creation rule, comma separated (`age1...,age1...`) or as a list
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum KeyList {
+    Joined(String),
+    List(Vec<String>),
+}
+
+impl KeyList {
+    pub fn keys(&self) -> Vec<String> {
+        let keys: Box<dyn Iterator<Item = &str>> = match self {
+            KeyList::Joined(keys) => Box::new(keys.split(',')),
+            KeyList::List(keys) => Box::new(keys.iter().map(String::as_str)),
+        };
+        keys.map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys().is_empty()
+    }
+
+    /// Append a key, written the way the list already is
+    pub fn push(&mut self, key: &str) {
+        match self {
+            KeyList::Joined(keys) if keys.trim().is_empty() => *keys = key.to_string(),
+            KeyList::Joined(keys) => *keys = format!("{},{}", keys, key),
+            KeyList::List(keys) => keys.push(key.to_string()),
+        }
+    }
+
+    /// Replace every key with what `f` makes of it
+    pub fn map(&mut self, f: impl Fn(&str) -> String) {
+        match self {
+            KeyList::Joined(keys) => {
+                let mapped: Vec<String> = keys.split(',').map(|k| f(k.trim())).collect();
+                *keys = mapped.join(",");
+            }
+            KeyList::List(keys) => {
+                for key in keys {
+                    *key = f(key.trim());
+                }
+            }
+        }
+    }
+
+    /// Keep only the keys `f` returns true for
+    pub fn retain(&mut self, f: impl Fn(&str) -> bool) {
+        match self {
+            KeyList::Joined(keys) => {
+                let kept: Vec<&str> = keys
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|k| !k.is_empty() && f(k))
+                    .collect();
+                *keys = kept.join(",");
+            }
+            KeyList::List(keys) => keys.retain(|k| f(k.trim())),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// A key group, sops needs keys from `shamir_threshold` of a rule's groups to decrypt
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct KeyGroup {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub age: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pgp: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kms: Vec<KmsKey>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gcp_kms: Vec<GcpKmsKey>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub azure_keyvault: Vec<AzureKeyVaultKey>,
+    /// Vault transit URIs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hc_vault: Vec<String>,
+    #[serde(flatten)]
+    pub other: Mapping,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct KmsKey {
+    pub arn: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<BTreeMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aws_profile: Option<String>,
+    #[serde(flatten)]
+    pub other: Mapping,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct GcpKmsKey {
+    pub resource_id: String,
+    #[serde(flatten)]
+    pub other: Mapping,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct AzureKeyVaultKey {
+    #[serde(rename = "vaultUrl")]
+    pub vault_url: String,
+    pub key: String,
+    pub version: String,
+    #[serde(flatten)]
+    pub other: Mapping,
+}
+
+/// Where `sops publish` sends files, and the rule it re-encrypts them with
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DestinationRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_regex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s3_bucket: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s3_prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gcs_bucket: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gcs_prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vault_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vault_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vault_kv_mount_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vault_kv_version: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recreation_rule: Option<CreationRule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub omit_extensions: Option<bool>,
+    #[serde(flatten)]
+    pub other: Mapping,
+}
+
+impl DestinationRule {
+    /// Where the files go, for display
+    pub fn destination(&self) -> String {
+        let join = |prefix: &str, bucket: &str, path: &Option<String>| {
+            format!("{}{}/{}", prefix, bucket, path.as_deref().unwrap_or(""))
+        };
+        if let Some(bucket) = &self.s3_bucket {
+            join("s3://", bucket, &self.s3_prefix)
+        } else if let Some(bucket) = &self.gcs_bucket {
+            join("gs://", bucket, &self.gcs_prefix)
+        } else if let Some(address) = &self.vault_address {
+            join("", address, &self.vault_path)
+        } else {
+            self.vault_path.clone().unwrap_or_default()
+        }
+    }
+}
+
+/// Per format settings sops writes files with
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Stores {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yaml: Option<StoreConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json: Option<StoreConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json_binary: Option<StoreConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dotenv: Option<StoreConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ini: Option<StoreConfig>,
+    #[serde(flatten)]
+    pub other: Mapping,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct StoreConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub indent: Option<usize>,
+    #[serde(flatten)]
+    pub other: Mapping,
+}
+
+impl CreationRule {
+    /// All age recipients of this rule as written, splitting the comma separated `age` field like
+    /// sops does. Entries may be names from `recipients`, see `SopsConfig::rule_recipients`.
+    pub fn age_recipients(&self) -> Vec<String> {
+        let direct = self.age.iter().flat_map(KeyList::keys);
+        let grouped = self
+            .key_groups
+            .iter()
+            .flat_map(|group| group.age.iter())
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty());
+
+        direct.chain(grouped).collect()
+    }
+
+    /// The key types other than age the rule encrypts for (`pgp`, `kms`, ...)
+    pub fn other_key_types(&self) -> Vec<&'static str> {
+        let has = |list: &Option<KeyList>| list.as_ref().is_some_and(|l| !l.is_empty());
+        let groups = &self.key_groups;
+        [
+            (
+                "pgp",
+                has(&self.pgp) || groups.iter().any(|g| !g.pgp.is_empty()),
+            ),
+            (
+                "kms",
+                has(&self.kms) || groups.iter().any(|g| !g.kms.is_empty()),
+            ),
+            (
+                "gcp_kms",
+                has(&self.gcp_kms) || groups.iter().any(|g| !g.gcp_kms.is_empty()),
+            ),
+            (
+                "azure_keyvault",
+                has(&self.azure_keyvault) || groups.iter().any(|g| !g.azure_keyvault.is_empty()),
+            ),
+            (
+                "hc_vault",
+                has(&self.hc_vault_transit_uri) || groups.iter().any(|g| !g.hc_vault.is_empty()),
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, used)| *used)
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    /// Returns why files under this rule can't be encrypted natively, if that is the case
+    pub fn requires_sops(&self) -> Option<String> {
+        if self.key_groups.len() > 1 {
+            return Some("rule uses Shamir secret sharing across key groups".to_string());
+        }
+        let other = self.other_key_types();
+        if !other.is_empty() {
+            return Some(format!("rule also encrypts for {} keys", other.join(", ")));
+        }
+        if self.encrypted_comment_regex.is_some() || self.unencrypted_comment_regex.is_some() {
+            return Some("rule encrypts comments".to_string());
+        }
+        None
+    }
+
+    /// Add an age recipient (a key or a name) to the rule's key group if it uses one, to `age`
+    /// otherwise. Returns false if the rule already has it.
+    pub fn add_age_recipient(&mut self, key: &str) -> Result<bool, String> {
+        if self.age_recipients().iter().any(|k| k == key) {
+            return Ok(false);
+        }
+
+        match self.key_groups.len() {
+            0 => {}
+            1 if self.age.is_none() => {
+                self.key_groups[0].age.push(key.to_string());
+                return Ok(true);
+            }
+            1 => {}
+            _ => {
+                return Err(
+                    "the rule has several key groups, add the key to the right one by hand"
+                        .to_string(),
+                );
+            }
+        }
+
+        self.age
+            .get_or_insert_with(|| KeyList::Joined(String::new()))
+            .push(key);
+        Ok(true)
+    }
+
+    /// Remove an age recipient (a key or a name) from `age` and every key group.
+    /// Returns false if the rule didn't have it.
+    pub fn remove_age_recipient(&mut self, key: &str) -> bool {
+        let before = self.age_recipients().len();
+
+        if let Some(age) = &mut self.age {
+            age.retain(|k| k != key);
+            if age.is_empty() {
+                self.age = None;
+            }
+        }
+        for group in &mut self.key_groups {
+            group.age.retain(|k| k.trim() != key);
+        }
+
+        self.age_recipients().len() != before
+    }
+
+    /// Replace every age recipient (a key or a name) with what `f` makes of it
+    pub fn map_age_recipients(&mut self, f: impl Fn(&str) -> String) {
+        if let Some(age) = &mut self.age {
+            age.map(&f);
+        }
+        for key in self.key_groups.iter_mut().flat_map(|g| g.age.iter_mut()) {
+            *key = f(key.trim());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::sops_document::{SopsDocument, SopsMetadata};
+    use age::x25519::Identity;
+
+    fn team_config(alice: &str) -> SopsConfig {
+        serde_yaml::from_str(&format!(
+            "creation_rules:\n  - path_regex: ^prod/\n    age: alice\ndestination_rules:\n  - path_regex: ^prod/\n    recreation_rule:\n      age: alice\nrecipients:\n  alice: {}\n",
+            alice
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn rules_encrypt_for_the_keys_of_named_recipients() {
+        let alice = Identity::generate();
+        let key = alice.to_public().to_string();
+        let config = team_config(&key);
+        let rule = &config.creation_rules[0];
+        assert_eq!(config.rule_recipients(rule), vec![key.clone()]);
+
+        let tree: serde_yaml::Value = serde_yaml::from_str("password: hunter2\n").unwrap();
+        let document = SopsDocument::encrypt(
+            tree.clone(),
+            SopsFormat::Yaml,
+            &config.rule_recipients(rule),
+            SopsMetadata::for_rule(rule),
+        )
+        .unwrap();
+        assert_eq!(document.metadata.recipients(), vec![key]);
+        assert_eq!(document.decrypt(&alice).unwrap(), tree);
+    }
+
+    #[test]
+    fn sops_gets_the_keys_instead_of_the_names() {
+        let key = Identity::generate().to_public().to_string();
+        let config = team_config(&key);
+        assert!(config.uses_recipient_names());
+
+        let expanded = config.expanded();
+        assert!(!expanded.uses_recipient_names());
+        assert!(expanded.recipients.is_empty());
+        assert_eq!(
+            expanded.creation_rules[0].age_recipients(),
+            vec![key.clone()]
+        );
+        let recreation = expanded.destination_rules[0]
+            .recreation_rule
+            .as_ref()
+            .unwrap();
+        assert_eq!(recreation.age_recipients(), vec![key]);
+    }
 }
@@ -0,0 +1,201 @@
+use serde_yaml::{Mapping, Value};
+
+/// The outcome of a three-way merge of two decrypted trees
+pub struct MergedTree {
+    pub tree: Value,
+    /// Key paths (joined with `.`) that were changed differently on both sides
+    pub conflicts: Vec<String>,
+}
+
+/// Merge `ours` and `theirs` against their common ancestor key by key. A key changed on only
+/// one side takes that change, keys changed differently on both sides get a value with
+/// git-style conflict markers. Without a base, keys both sides have with different values conflict.
+pub fn merge_trees(base: Option<&Value>, ours: &Value, theirs: &Value) -> MergedTree {
+    let mut conflicts = Vec::new();
+    let tree = merge_value(
+        base,
+        Some(ours),
+        Some(theirs),
+        &mut Vec::new(),
+        &mut conflicts,
+    )
+    .unwrap_or_else(|| Value::Mapping(Mapping::new()));
+
+    MergedTree { tree, conflicts }
+}
+
+/// Merge a single value, None meaning the key doesn't exist (anymore) on that side
+fn merge_value(
+    base: Option<&Value>,
+    ours: Option<&Value>,
+    theirs: Option<&Value>,
+    path: &mut Vec<String>,
+    conflicts: &mut Vec<String>,
+) -> Option<Value> {
+    if ours == theirs {
+        return ours.cloned();
+    }
+    if base == ours {
+        return theirs.cloned();
+    }
+    if base == theirs {
+        return ours.cloned();
+    }
+
+    // Both sides changed a mapping: merge it key by key
+    if let (Some(Value::Mapping(o)), Some(Value::Mapping(t))) = (ours, theirs) {
+        let b = match base {
+            Some(Value::Mapping(b)) => Some(b),
+            _ => None,
+        };
+        return Some(Value::Mapping(merge_mappings(b, o, t, path, conflicts)));
+    }
+
+    conflicts.push(path.join("."));
+    Some(Value::String(conflict_marker(ours, theirs)))
+}
+
+fn merge_mappings(
+    base: Option<&Mapping>,
+    ours: &Mapping,
+    theirs: &Mapping,
+    path: &mut Vec<String>,
+    conflicts: &mut Vec<String>,
+) -> Mapping {
+    // Keep our key order and append keys only they have
+    let keys = ours
+        .keys()
+        .chain(theirs.keys().filter(|k| !ours.contains_key(*k)));
+
+    let mut merged = Mapping::new();
+    for key in keys {
+        path.push(key_label(key));
+        let value = merge_value(
+            base.and_then(|b| b.get(key)),
+            ours.get(key),
+            theirs.get(key),
+            path,
+            conflicts,
+        );
+        path.pop();
+
+        if let Some(value) = value {
+            merged.insert(key.clone(), value);
+        }
+    }
+    merged
+}
+
+/// Both versions of a conflicting value, delimited like git does for text files
+fn conflict_marker(ours: Option<&Value>, theirs: Option<&Value>) -> String {
+    format!(
+        "<<<<<<< ours\n{}=======\n{}>>>>>>> theirs\n",
+        side_text(ours),
+        side_text(theirs)
+    )
+}
+
+fn side_text(value: Option<&Value>) -> String {
+    let text = match value {
+        None => return String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Null) => "null".to_string(),
+        Some(Value::Bool(b)) => b.to_string(),
+        Some(Value::Number(n)) => n.to_string(),
+        Some(other) => serde_yaml::to_string(other).unwrap_or_default(),
+    };
+
+    if text.ends_with('\n') {
+        text
+    } else {
+        format!("{}\n", text)
+    }
+}
+
+fn key_label(key: &Value) -> String {
+    match key {
+        Value::String(s) => s.clone(),
+        other => serde_yaml::to_string(other)
+            .map(|s| s.trim_end().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(text: &str) -> Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    fn merge(base: Option<&str>, ours: &str, theirs: &str) -> MergedTree {
+        merge_trees(base.map(yaml).as_ref(), &yaml(ours), &yaml(theirs))
+    }
+
+    #[test]
+    fn takes_changes_made_on_one_side() {
+        let merged = merge(Some("a: 1\nb: 1\n"), "a: 2\nb: 1\n", "a: 1\nb: 3\n");
+        assert_eq!(merged.tree, yaml("a: 2\nb: 3\n"));
+        assert!(merged.conflicts.is_empty());
+    }
+
+    #[test]
+    fn merges_nested_maps_key_by_key() {
+        let base = "db:\n  user: app\n  password: old\n  tls:\n    enabled: false\n";
+        let ours = "db:\n  user: admin\n  password: old\n  tls:\n    enabled: false\n";
+        let theirs = "db:\n  user: app\n  password: new\n  tls:\n    enabled: true\n    ca: x\n";
+        let merged = merge(Some(base), ours, theirs);
+        assert_eq!(
+            merged.tree,
+            yaml("db:\n  user: admin\n  password: new\n  tls:\n    enabled: true\n    ca: x\n")
+        );
+        assert!(merged.conflicts.is_empty());
+    }
+
+    #[test]
+    fn marks_keys_changed_differently_on_both_sides() {
+        let merged = merge(
+            Some("db:\n  password: old\n"),
+            "db:\n  password: ours\n",
+            "db:\n  password: theirs\n",
+        );
+        assert_eq!(merged.conflicts, vec!["db.password"]);
+        assert_eq!(
+            merged.tree["db"]["password"],
+            Value::from("<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\n")
+        );
+    }
+
+    #[test]
+    fn applies_deletions() {
+        let merged = merge(Some("a: 1\nb: 1\nc: 1\n"), "a: 1\nc: 1\n", "a: 1\nb: 1\n");
+        assert_eq!(merged.tree, yaml("a: 1\n"));
+        assert!(merged.conflicts.is_empty());
+    }
+
+    #[test]
+    fn conflicts_when_one_side_deletes_what_the_other_changed() {
+        let merged = merge(Some("a: 1\nb: 1\n"), "a: 1\n", "a: 1\nb: 2\n");
+        assert_eq!(merged.conflicts, vec!["b"]);
+        assert_eq!(
+            merged.tree["b"],
+            Value::from("<<<<<<< ours\n=======\n2\n>>>>>>> theirs\n")
+        );
+    }
+
+    #[test]
+    fn keeps_our_key_order_and_appends_theirs() {
+        let merged = merge(Some("b: 1\n"), "b: 1\na: 1\n", "c: 1\nb: 1\n");
+        let keys: Vec<&Value> = merged.tree.as_mapping().unwrap().keys().collect();
+        assert_eq!(keys, vec!["b", "a", "c"]);
+    }
+
+    #[test]
+    fn without_a_base_only_differing_values_conflict() {
+        let merged = merge(None, "a: 1\nb: 1\n", "a: 1\nb: 2\nc: 3\n");
+        assert_eq!(merged.conflicts, vec!["b"]);
+        assert_eq!(merged.tree["a"], Value::from(1));
+        assert_eq!(merged.tree["c"], Value::from(3));
+    }
+}
@@ -0,0 +1,465 @@
+use super::yaml_tree::{YamlKind, YamlNode, parse_yaml};
+use saphyr_parser::ScalarStyle;
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+
+/// Apply the difference between `old` and `new` to `original`, the YAML text `old` was read
+/// from. Only what changed is rewritten, so comments, key order, quoting and everything else
+/// stay as they were. Returns an error for documents that can't be patched this way (several
+/// documents, overlapping changes, ...).
+pub fn update_yaml(original: &str, old: &Value, new: &Value) -> Result<String, String> {
+    let root = parse_yaml(original).map_err(|e| e.to_string())?;
+    let mut editor = Editor {
+        text: original,
+        edits: Vec::new(),
+    };
+    editor.diff(&root, Parent::Root, old, new)?;
+
+    // Insertions at the same offset keep the order they were made in
+    let mut edits: Vec<(usize, Edit)> = editor.edits.into_iter().enumerate().collect();
+    edits.sort_by_key(|(order, edit)| (edit.start, *order));
+    if edits.windows(2).any(|w| w[1].1.start < w[0].1.end) {
+        return Err("overlapping changes".to_string());
+    }
+
+    // Last edit first, so the offsets of the others stay valid
+    let mut text = original.to_string();
+    for (_, edit) in edits.into_iter().rev() {
+        text.replace_range(edit.start..edit.end, &edit.text);
+    }
+    Ok(text)
+}
+
+/// Where a node sits, which decides how it can be replaced
+#[derive(Clone, Copy)]
+enum Parent<'a> {
+    Root,
+    Sequence,
+    Mapping { key: &'a YamlNode },
+}
+
+struct Edit {
+    start: usize,
+    end: usize,
+    text: String,
+}
+
+struct Editor<'a> {
+    text: &'a str,
+    edits: Vec<Edit>,
+}
+
+impl Editor<'_> {
+    fn diff(
+        &mut self,
+        node: &YamlNode,
+        parent: Parent,
+        old: &Value,
+        new: &Value,
+    ) -> Result<(), String> {
+        if old == new {
+            return Ok(());
+        }
+
+        // Block collections are patched entry by entry, everything else is replaced
+        if !node.flow {
+            match (&node.kind, old, new) {
+                (YamlKind::Mapping(entries), Value::Mapping(old), Value::Mapping(new))
+                    if !new.is_empty() =>
+                {
+                    return self.diff_mapping(entries, old, new);
+                }
+                (YamlKind::Sequence(items), Value::Sequence(old), Value::Sequence(new))
+                    if !new.is_empty() && items.len() == old.len() =>
+                {
+                    return self.diff_sequence(items, old, new);
+                }
+                _ => {}
+            }
+        }
+        self.replace(node, parent, new)
+    }
+
+    fn diff_mapping(
+        &mut self,
+        entries: &[(YamlNode, YamlNode)],
+        old: &Mapping,
+        new: &Mapping,
+    ) -> Result<(), String> {
+        for (i, (key, value)) in entries.iter().enumerate() {
+            let YamlKind::Scalar { value: name, .. } = &key.kind else {
+                continue;
+            };
+            match (lookup(old, name), lookup(new, name)) {
+                (Some(o), Some(n)) => self.diff(value, Parent::Mapping { key }, o, n)?,
+                (Some(_), None) => self.remove_entry(entries, i)?,
+                // Written without a value, like `age:`
+                (None, Some(n)) => self.diff(value, Parent::Mapping { key }, &Value::Null, n)?,
+                (None, None) => {}
+            }
+        }
+
+        for (name, value) in new {
+            let written = entries.iter().any(|(key, _)| {
+                matches!(&key.kind, YamlKind::Scalar { value: k, .. } if key_matches(name, k))
+            });
+            if !written && old.get(name) != Some(value) {
+                let mut entry = Mapping::new();
+                entry.insert(name.clone(), value.clone());
+                self.insert_after(entries[entries.len() - 1].1.end, entries[0].0.start, &entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Items are matched up by value first, so inserting or removing one leaves the others alone
+    fn diff_sequence(
+        &mut self,
+        items: &[YamlNode],
+        old: &[Value],
+        new: &[Value],
+    ) -> Result<(), String> {
+        let dash = self.dash_column(&items[0])?;
+        let mut matches = common_items(old, new);
+        matches.push((old.len(), new.len()));
+
+        let (mut o, mut n) = (0, 0);
+        for (next_o, next_n) in matches {
+            // Items between two matches are changed in place where possible
+            while o < next_o && n < next_n {
+                self.diff(&items[o], Parent::Sequence, &old[o], &new[n])?;
+                o += 1;
+                n += 1;
+            }
+            while o < next_o {
+                self.remove_item(&items[o])?;
+                o += 1;
+            }
+            while n < next_n {
+                match items.get(next_o) {
+                    Some(before) => {
+                        let at = self.line_start(before.start);
+                        self.insert(at, dash, &[&new[n]])?;
+                    }
+                    None => self.insert_after(items[items.len() - 1].end, dash, &[&new[n]])?,
+                }
+                n += 1;
+            }
+            o += 1;
+            n += 1;
+        }
+        Ok(())
+    }
+
+    fn replace(&mut self, node: &YamlNode, parent: Parent, new: &Value) -> Result<(), String> {
+        let style = match &node.kind {
+            YamlKind::Scalar { style, .. } => Some(*style),
+            _ => None,
+        };
+
+        // `key:` without a value, the value goes after the colon. The parser reads it as a
+        // plain `~` that covers no text.
+        if matches!(node.kind, YamlKind::Scalar { .. }) && node.start == node.end {
+            let Parent::Mapping { key } = parent else {
+                return Err("can't fill in an empty sequence item".to_string());
+            };
+            let colon = self.text[key.end..]
+                .find(':')
+                .map(|i| key.end + i + 1)
+                .ok_or("missing colon after a key")?;
+            return self.edit(colon, colon, format!(" {}", inline(new, None)?));
+        }
+
+        if node.flow || style.is_some() || matches!(node.kind, YamlKind::Alias) {
+            return self.edit(node.start, node.end, inline(new, style)?);
+        }
+        match parent {
+            Parent::Mapping { key } => {
+                self.edit(key.end, node.end, format!(": {}", inline(new, None)?))
+            }
+            Parent::Sequence => self.edit(node.start, node.end, inline(new, None)?),
+            Parent::Root => Err("can't replace the whole document".to_string()),
+        }
+    }
+
+    fn remove_entry(&mut self, entries: &[(YamlNode, YamlNode)], i: usize) -> Result<(), String> {
+        let (key, value) = &entries[i];
+        let line = self.line_start(key.start);
+        if self.text[line..key.start].trim().is_empty() {
+            return self.edit(line, self.line_end(value.end), String::new());
+        }
+
+        // The first key of a sequence item (`- path_regex: ...`), the next key moves up
+        match entries.get(i + 1) {
+            Some((next, _)) => self.edit(key.start, next.start, String::new()),
+            None => Err("can't remove the last key of a sequence item".to_string()),
+        }
+    }
+
+    fn remove_item(&mut self, item: &YamlNode) -> Result<(), String> {
+        self.dash_column(item)?;
+        let line = self.line_start(item.start);
+        self.edit(line, self.line_end(item.end), String::new())
+    }
+
+    /// Insert `value` as block YAML on the line after `end`
+    fn insert_after(
+        &mut self,
+        end: usize,
+        indent_from: usize,
+        value: &impl Serialize,
+    ) -> Result<(), String> {
+        let indent = indent_from - self.line_start(indent_from);
+        self.insert(self.line_end(end), indent, value)
+    }
+
+    fn insert(&mut self, at: usize, indent: usize, value: &impl Serialize) -> Result<(), String> {
+        let yaml =
+            serde_yaml::to_string(value).map_err(|e| format!("Failed to serialize YAML: {}", e))?;
+        let mut text = String::new();
+        if at == self.text.len() && !self.text.is_empty() && !self.text.ends_with('\n') {
+            text.push('\n');
+        }
+        for line in yaml.lines() {
+            text.push_str(&" ".repeat(indent));
+            text.push_str(line);
+            text.push('\n');
+        }
+        self.edit(at, at, text)
+    }
+
+    fn edit(&mut self, start: usize, end: usize, text: String) -> Result<(), String> {
+        self.edits.push(Edit { start, end, text });
+        Ok(())
+    }
+
+    /// The column of the `- ` in front of a block sequence item, which has to start its line
+    fn dash_column(&self, item: &YamlNode) -> Result<usize, String> {
+        let line = self.line_start(item.start);
+        let prefix = self.text[line..item.start].trim_end();
+        match prefix.strip_suffix('-') {
+            Some(indent) if indent.trim().is_empty() => Ok(indent.len()),
+            _ => Err("sequence item doesn't start its line".to_string()),
+        }
+    }
+
+    fn line_start(&self, offset: usize) -> usize {
+        self.text[..offset].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    /// The start of the line after `offset`
+    fn line_end(&self, offset: usize) -> usize {
+        self.text[offset..]
+            .find('\n')
+            .map_or(self.text.len(), |i| offset + i + 1)
+    }
+}
+
+/// A value on a single line, keeping the quoting of the scalar it replaces
+fn inline(value: &Value, style: Option<ScalarStyle>) -> Result<String, String> {
+    let json = || serde_json::to_string(value).map_err(|e| format!("Failed to serialize: {}", e));
+    match (value, style) {
+        (Value::String(_), Some(ScalarStyle::DoubleQuoted)) => json(),
+        (Value::String(s), Some(ScalarStyle::SingleQuoted)) if !s.contains('\n') => {
+            Ok(format!("'{}'", s.replace('\'', "''")))
+        }
+        (Value::Sequence(_) | Value::Mapping(_), _) => json(),
+        _ => {
+            let yaml = serde_yaml::to_string(value)
+                .map_err(|e| format!("Failed to serialize YAML: {}", e))?;
+            let yaml = yaml.trim_end_matches('\n');
+            // Multi-line strings would need block scalars, a quoted string does it on one line
+            if yaml.contains('\n') {
+                json()
+            } else {
+                Ok(yaml.to_string())
+            }
+        }
+    }
+}
+
+fn lookup<'a>(mapping: &'a Mapping, key: &str) -> Option<&'a Value> {
+    mapping
+        .iter()
+        .find(|(name, _)| key_matches(name, key))
+        .map(|(_, value)| value)
+}
+
+/// Keys are compared as written, `1:` is the number 1
+fn key_matches(name: &Value, written: &str) -> bool {
+    match name {
+        Value::String(s) => s == written,
+        Value::Number(n) => n.to_string() == written,
+        Value::Bool(b) => b.to_string() == written,
+        _ => false,
+    }
+}
+
+/// Index pairs of the longest run of items `old` and `new` have in common, in order
+fn common_items(old: &[Value], new: &[Value]) -> Vec<(usize, usize)> {
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Patch `original` with the change `change` makes to its value, and check that the result
+    /// reads back as the changed value
+    fn patch(original: &str, change: impl FnOnce(&mut Value)) -> String {
+        let old: Value = serde_yaml::from_str(original).unwrap();
+        let mut new = old.clone();
+        change(&mut new);
+        let patched = update_yaml(original, &old, &new).unwrap();
+        assert_eq!(
+            serde_yaml::from_str::<Value>(&patched).unwrap(),
+            new,
+            "{}",
+            patched
+        );
+        patched
+    }
+
+    fn rules(value: &mut Value) -> &mut Vec<Value> {
+        value["creation_rules"].as_sequence_mut().unwrap()
+    }
+
+    #[test]
+    fn keeps_comments_around_a_changed_scalar() {
+        let original = "# project keys\nonepassworditem: op://V/old/Private Key # the main key\n\n# rules\ncreation_rules: []\n";
+        let patched = patch(original, |v| {
+            v["onepassworditem"] = Value::from("op://V/new/Private Key")
+        });
+        assert_eq!(
+            patched,
+            "# project keys\nonepassworditem: op://V/new/Private Key # the main key\n\n# rules\ncreation_rules: []\n"
+        );
+    }
+
+    #[test]
+    fn keeps_the_quoting_of_scalars() {
+        let original = "a: \"double\"\nb: 'single'\nc: plain\n";
+        let patched = patch(original, |v| {
+            v["a"] = Value::from("one");
+            v["b"] = Value::from("it's");
+            v["c"] = Value::from("three");
+        });
+        assert_eq!(patched, "a: \"one\"\nb: 'it''s'\nc: three\n");
+    }
+
+    #[test]
+    fn replaces_flow_sequences_inline() {
+        let original = "keys: [age1a, age1b] # inline\nother: 1\n";
+        let patched = patch(original, |v| {
+            v["keys"]
+                .as_sequence_mut()
+                .unwrap()
+                .push(Value::from("age1c"))
+        });
+        assert_eq!(
+            patched,
+            "keys: [\"age1a\",\"age1b\",\"age1c\"] # inline\nother: 1\n"
+        );
+    }
+
+    #[test]
+    fn adds_and_removes_block_sequence_items() {
+        let original = "keys:\n  - age1a # alice\n  - age1b # bob\nother: 1\n";
+        let added = patch(original, |v| {
+            v["keys"]
+                .as_sequence_mut()
+                .unwrap()
+                .push(Value::from("age1c"))
+        });
+        assert_eq!(
+            added,
+            "keys:\n  - age1a # alice\n  - age1b # bob\n  - age1c\nother: 1\n"
+        );
+
+        let removed = patch(original, |v| {
+            v["keys"].as_sequence_mut().unwrap().pop();
+        });
+        assert_eq!(removed, "keys:\n  - age1a # alice\nother: 1\n");
+
+        let first_removed = patch(original, |v| {
+            v["keys"].as_sequence_mut().unwrap().remove(0);
+        });
+        assert_eq!(first_removed, "keys:\n  - age1b # bob\nother: 1\n");
+    }
+
+    #[test]
+    fn adds_and_removes_keys_in_the_last_rule() {
+        let original = "creation_rules:\n  # prod first\n  - path_regex: ^prod/\n    age: age1prod\n  - path_regex: ^dev/\n    age: age1dev # dev key\n";
+        let added = patch(original, |v| {
+            rules(v)[1]["onepassworditem"] = Value::from("dev");
+        });
+        assert_eq!(
+            added,
+            "creation_rules:\n  # prod first\n  - path_regex: ^prod/\n    age: age1prod\n  - path_regex: ^dev/\n    age: age1dev # dev key\n    onepassworditem: dev\n"
+        );
+
+        let removed = patch(original, |v| {
+            rules(v)[1].as_mapping_mut().unwrap().remove("age");
+        });
+        assert_eq!(
+            removed,
+            "creation_rules:\n  # prod first\n  - path_regex: ^prod/\n    age: age1prod\n  - path_regex: ^dev/\n"
+        );
+    }
+
+    #[test]
+    fn appends_a_rule_after_the_last_one() {
+        let original = "creation_rules:\n  - path_regex: ^prod/\n    age: age1prod\n";
+        let patched = patch(original, |v| {
+            let mut rule = Mapping::new();
+            rule.insert("age".into(), "age1all".into());
+            rules(v).push(Value::Mapping(rule));
+        });
+        assert_eq!(
+            patched,
+            "creation_rules:\n  - path_regex: ^prod/\n    age: age1prod\n  - age: age1all\n"
+        );
+    }
+
+    #[test]
+    fn fills_in_a_key_without_a_value() {
+        let original = "creation_rules:\n  - path_regex: x\n    age:\n";
+        let patched = patch(original, |v| rules(v)[0]["age"] = Value::from("age1x"));
+        assert_eq!(
+            patched,
+            "creation_rules:\n  - path_regex: x\n    age: age1x\n"
+        );
+    }
+
+    #[test]
+    fn refuses_what_it_cant_patch() {
+        let original = "# rules\na: 1\n";
+        let old: Value = serde_yaml::from_str(original).unwrap();
+        let new: Value = serde_yaml::from_str("- 1\n").unwrap();
+        assert!(update_yaml(original, &old, &new).is_err());
+    }
+}
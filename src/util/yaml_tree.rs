@@ -0,0 +1,199 @@
+use saphyr_parser::{Event, Parser, ScalarStyle};
+use std::fmt;
+
+/// A node of a YAML document and the bytes of the source it covers, without trailing comments
+pub struct YamlNode {
+    pub start: usize,
+    pub end: usize,
+    /// `[...]` and `{...}`
+    pub flow: bool,
+    pub kind: YamlKind,
+}
+
+pub enum YamlKind {
+    Scalar { value: String, style: ScalarStyle },
+    Alias,
+    Sequence(Vec<YamlNode>),
+    Mapping(Vec<(YamlNode, YamlNode)>),
+}
+
+impl YamlNode {
+    /// The entry of a mapping with a scalar key, the last one if the key is repeated like
+    /// serde_yaml reads it
+    pub fn entry(&self, key: &str) -> Option<&(YamlNode, YamlNode)> {
+        match &self.kind {
+            YamlKind::Mapping(entries) => {
+                entries.iter().rev().find(|(k, _)| k.scalar() == Some(key))
+            }
+            _ => None,
+        }
+    }
+
+    /// The value of a mapping entry
+    pub fn get(&self, key: &str) -> Option<&YamlNode> {
+        self.entry(key).map(|(_, value)| value)
+    }
+
+    /// An item of a sequence
+    pub fn item(&self, index: usize) -> Option<&YamlNode> {
+        match &self.kind {
+            YamlKind::Sequence(items) => items.get(index),
+            _ => None,
+        }
+    }
+
+    pub fn scalar(&self) -> Option<&str> {
+        match &self.kind {
+            YamlKind::Scalar { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// A syntax error, at a byte offset into the source
+pub struct YamlError {
+    pub message: String,
+    pub offset: usize,
+}
+
+impl fmt::Display for YamlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Parse a single YAML document into a tree of nodes that know where they are in `text`
+pub fn parse_yaml(text: &str) -> Result<YamlNode, YamlError> {
+    // The parser counts characters, callers need byte offsets
+    let offsets: Vec<usize> = text
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(std::iter::once(text.len()))
+        .collect();
+
+    let mut events = Vec::new();
+    for event in Parser::new_from_str(text) {
+        let (event, span) = event.map_err(|e| YamlError {
+            message: e.info().to_string(),
+            offset: offsets[e.marker().index().min(offsets.len() - 1)],
+        })?;
+        events.push((
+            event,
+            offsets[span.start.index()],
+            offsets[span.end.index()],
+        ));
+    }
+
+    let mut starts = events
+        .iter()
+        .enumerate()
+        .filter(|(_, (event, _, _))| matches!(event, Event::DocumentStart(_)));
+    let next = match (starts.next(), starts.next()) {
+        (Some((i, _)), None) => i + 1,
+        (_, Some((_, (_, offset, _)))) => {
+            return Err(YamlError {
+                message: "expected a single YAML document".to_string(),
+                offset: *offset,
+            });
+        }
+        (None, None) => {
+            return Err(YamlError {
+                message: "the file is empty".to_string(),
+                offset: 0,
+            });
+        }
+    };
+
+    Tree { text, events, next }.node()
+}
+
+/// The parser's events with their byte offsets, turned into nodes
+struct Tree<'a> {
+    text: &'a str,
+    events: Vec<(Event<'a>, usize, usize)>,
+    next: usize,
+}
+
+impl Tree<'_> {
+    fn node(&mut self) -> Result<YamlNode, YamlError> {
+        let Some((event, start, end)) = self.events.get(self.next).cloned() else {
+            return Err(YamlError {
+                message: "unexpected end of the YAML document".to_string(),
+                offset: self.text.len(),
+            });
+        };
+        self.next += 1;
+        let flow = matches!(self.text[start..].chars().next(), Some('[' | '{'));
+
+        let (kind, end) = match event {
+            Event::Scalar(value, style, _, _) => (
+                YamlKind::Scalar {
+                    value: value.into_owned(),
+                    style,
+                },
+                end,
+            ),
+            Event::Alias(_) => (YamlKind::Alias, end),
+            Event::SequenceStart(_, _) => {
+                let mut items = Vec::new();
+                while let Some(child) = self.child(Event::SequenceEnd)? {
+                    items.push(child);
+                }
+                let end = self.collection_end(flow, items.last().map(|n| n.end), start);
+                (YamlKind::Sequence(items), end)
+            }
+            Event::MappingStart(_, _) => {
+                let mut entries = Vec::new();
+                while let Some(key) = self.child(Event::MappingEnd)? {
+                    entries.push((key, self.node()?));
+                }
+                let end = self.collection_end(flow, entries.last().map(|(_, v)| v.end), start);
+                (YamlKind::Mapping(entries), end)
+            }
+            event => {
+                return Err(YamlError {
+                    message: format!("unexpected YAML event {:?}", event),
+                    offset: start,
+                });
+            }
+        };
+
+        Ok(YamlNode {
+            start,
+            end,
+            flow,
+            kind,
+        })
+    }
+
+    /// The next child of a collection, or None once its `end` event comes up
+    fn child(&mut self, end: Event) -> Result<Option<YamlNode>, YamlError> {
+        match self.events.get(self.next) {
+            Some((event, _, _)) if *event == end => {
+                self.next += 1;
+                Ok(None)
+            }
+            _ => self.node().map(Some),
+        }
+    }
+
+    /// Flow collections end after their closing bracket, where the end event just consumed
+    /// starts (its span runs on over a trailing comment), block collections with their last child
+    fn collection_end(&self, flow: bool, last_child: Option<usize>, start: usize) -> usize {
+        if flow {
+            self.events[self.next - 1].1 + 1
+        } else {
+            last_child.unwrap_or(start)
+        }
+    }
+}
+
+/// The 1-based line and column of a byte offset
+pub fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}